@@ -1,26 +1,34 @@
 use ttt_limited::*;
+/// Analyzes the setting given as the first argument, `3x4:3:4` by default, and writes the
+/// dictionary to the path given as the second one. By default it is the file the web app
+/// downloads for its 3x4 preset.
 fn main() {
-    let (setting, max_cnt) = (GameSetting::try_new(3, 4, 3, 12).unwrap(), usize::MAX);
-    //let (setting, max_cnt) = (GameSetting::try_new(3, 3).unwrap(), usize::MAX);
-    //let (setting, max_cnt) = (GameSetting::try_new(3, 4).unwrap(), usize::MAX);
-    //let analysis = analyze(setting, Default::default(), max_cnt);
-    let analysis = analyze(setting, Default::default(), max_cnt);
+    let mut args = env::args().skip(1);
+    let setting = args
+        .next()
+        .unwrap_or_else(|| "3x4:3:4".to_string())
+        .parse::<GameSetting>()
+        .unwrap();
+    let path = args
+        .next()
+        .unwrap_or_else(|| "analyzed_3x4_4.bin".to_string());
+    let analysis = analyze(Game::new(setting), usize::MAX);
     let a = analysis.analysis();
     println!("{:?}", a.len());
     println!("{:?}", a.values().filter(|a| a.is_winning()).count());
     println!("{:?}", a.values().filter(|a| a.is_losing()).count());
-    ff(analysis);
+    println!("{:?}", a.values().filter(|a| a.is_draw()).count());
+    ff(analysis, &path);
 }
 
 use postcard::to_io;
-use std::fs;
-pub fn ff(ad: AnalysisDictionary) {
-    //let foo = to_stdvec(&ad).unwrap();
+use std::{env, fs};
+pub fn ff(ad: AnalysisDictionary, path: &str) {
     let mut file = fs::OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
-        .open("ad.bin")
+        .open(path)
         .unwrap();
     to_io(&ad, &mut file).unwrap();
 }
@@ -1,10 +1,11 @@
 use leptos::*;
 use rand::{seq::SliceRandom, SeedableRng};
 use rand_xoshiro::Xoshiro256PlusPlus;
+use std::fmt::Write;
 use std::rc::Rc;
 use ttt_limited::*;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use wasm_bindgen_futures::JsFuture;
 use web_sys::js_sys::Uint8Array;
 use web_sys::{window, Response};
 
@@ -17,24 +18,45 @@ fn main() {
 enum SettingPreset {
     C3x3Limit3,
     C3x3Limit4,
+    C3x3Limit3Vs4,
+    C3x3Limit3Misere,
+    C3x3Limit4Misere,
+    C3x3TorusLimit3,
+    C3x3GravityLimit3,
     C3x3Normal,
     C3x4Limit4,
+    C4x4TorusLimit4,
+    C4x4GravityLimit4,
 }
 impl SettingPreset {
     fn to_str(self) -> &'static str {
         match self {
             SettingPreset::C3x3Limit3 => "3x3 Limit 3",
             SettingPreset::C3x3Limit4 => "3x3 Limit 4",
+            SettingPreset::C3x3Limit3Vs4 => "3x3 Limit 3/4",
+            SettingPreset::C3x3Limit3Misere => "3x3 Limit 3 Misere",
+            SettingPreset::C3x3Limit4Misere => "3x3 Limit 4 Misere",
+            SettingPreset::C3x3TorusLimit3 => "3x3 Torus Limit 3",
+            SettingPreset::C3x3GravityLimit3 => "3x3 Gravity Limit 3",
             SettingPreset::C3x3Normal => "3x3 Normal",
             SettingPreset::C3x4Limit4 => "3x4 Limit 4",
+            SettingPreset::C4x4TorusLimit4 => "4x4 Torus Limit 4",
+            SettingPreset::C4x4GravityLimit4 => "4x4 Gravity Limit 4",
         }
     }
     fn try_from_str(s: &str) -> Option<Self> {
         match s {
             "3x3 Limit 3" => SettingPreset::C3x3Limit3,
             "3x3 Limit 4" => SettingPreset::C3x3Limit4,
+            "3x3 Limit 3/4" => SettingPreset::C3x3Limit3Vs4,
+            "3x3 Limit 3 Misere" => SettingPreset::C3x3Limit3Misere,
+            "3x3 Limit 4 Misere" => SettingPreset::C3x3Limit4Misere,
+            "3x3 Torus Limit 3" => SettingPreset::C3x3TorusLimit3,
+            "3x3 Gravity Limit 3" => SettingPreset::C3x3GravityLimit3,
             "3x3 Normal" => SettingPreset::C3x3Normal,
             "3x4 Limit 4" => SettingPreset::C3x4Limit4,
+            "4x4 Torus Limit 4" => SettingPreset::C4x4TorusLimit4,
+            "4x4 Gravity Limit 4" => SettingPreset::C4x4GravityLimit4,
             _ => return None,
         }
         .into()
@@ -43,16 +65,50 @@ impl SettingPreset {
         vec![
             SettingPreset::C3x3Limit3,
             SettingPreset::C3x3Limit4,
+            SettingPreset::C3x3Limit3Vs4,
+            SettingPreset::C3x3Limit3Misere,
+            SettingPreset::C3x3Limit4Misere,
+            SettingPreset::C3x3TorusLimit3,
+            SettingPreset::C3x3GravityLimit3,
             SettingPreset::C3x3Normal,
             SettingPreset::C3x4Limit4,
+            SettingPreset::C4x4TorusLimit4,
+            SettingPreset::C4x4GravityLimit4,
         ]
     }
+    /// Where the analysis of the preset can be downloaded, for the presets that are analyzed
+    /// ahead of time rather than in the browser.
+    fn analysis_url(self) -> Option<&'static str> {
+        match self {
+            SettingPreset::C3x4Limit4 => Some("/analyzed_3x4_4.bin"),
+            _ => None,
+        }
+    }
     fn to_game_setting(self) -> GameSetting {
         match self {
             SettingPreset::C3x3Limit3 => GameSetting::try_new_normal_limited(3, 3).unwrap(),
             SettingPreset::C3x3Limit4 => GameSetting::try_new_normal_limited(3, 4).unwrap(),
+            SettingPreset::C3x3Limit3Vs4 => GameSetting::try_new_asymmetric(3, 3, 3, 3, 4).unwrap(),
+            SettingPreset::C3x3Limit3Misere => GameSetting::try_new_normal_limited(3, 3)
+                .unwrap()
+                .with_misere(true),
+            SettingPreset::C3x3Limit4Misere => GameSetting::try_new_normal_limited(3, 4)
+                .unwrap()
+                .with_misere(true),
+            SettingPreset::C3x3TorusLimit3 => GameSetting::try_new_normal_limited(3, 3)
+                .unwrap()
+                .with_torus(true),
+            SettingPreset::C3x3GravityLimit3 => GameSetting::try_new_normal_limited(3, 3)
+                .unwrap()
+                .with_gravity(true),
             SettingPreset::C3x3Normal => GameSetting::try_new_normal(3).unwrap(),
             SettingPreset::C3x4Limit4 => GameSetting::try_new(3, 4, 3, 4).unwrap(),
+            SettingPreset::C4x4TorusLimit4 => GameSetting::try_new_normal_limited(4, 4)
+                .unwrap()
+                .with_torus(true),
+            SettingPreset::C4x4GravityLimit4 => GameSetting::try_new_normal_limited(4, 4)
+                .unwrap()
+                .with_gravity(true),
         }
     }
 }
@@ -85,13 +141,83 @@ impl ShowMode {
     }
 }
 
+const SAVED_GAME_KEY: &str = "ttt-limited-game";
+const SAVED_PRESET_KEY: &str = "ttt-limited-preset";
+
+/// Reads the preset saved by `save_preset`, if there is a known one.
+fn load_saved_preset() -> Option<SettingPreset> {
+    let storage = window()?.local_storage().ok()??;
+    SettingPreset::try_from_str(&storage.get_item(SAVED_PRESET_KEY).ok()??)
+}
+
+/// Keeps the preset in the local storage, so that the game saved with it is loaded again
+/// after a reload.
+fn save_preset(preset: SettingPreset) {
+    let Some(storage) = window().and_then(|window| window.local_storage().ok().flatten()) else {
+        return;
+    };
+    let _ = storage.set_item(SAVED_PRESET_KEY, preset.to_str());
+}
+
+/// Reads the game saved by `save_game`, if there is a valid one.
+fn load_saved_game() -> Option<Game> {
+    let storage = window()?.local_storage().ok()??;
+    let hex = storage.get_item(SAVED_GAME_KEY).ok()??;
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    postcard::from_bytes(&bytes).ok()
+}
+
+/// Keeps the game in the local storage as hex-encoded postcard.
+fn save_game(game: &Game) {
+    let Some(storage) = window().and_then(|window| window.local_storage().ok().flatten()) else {
+        return;
+    };
+    let bytes = postcard::to_stdvec(game).unwrap();
+    let hex = bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    });
+    let _ = storage.set_item(SAVED_GAME_KEY, &hex);
+}
+
+/// Downloads and reads the analysis dictionary at `url`, telling what went wrong otherwise.
+async fn fetch_analysis(url: &str) -> Result<AnalysisDictionary, String> {
+    let download_error = |err: JsValue| format!("Could not download the analysis: {:?}", err);
+    let res = JsFuture::from(window().unwrap().fetch_with_str(url))
+        .await
+        .map_err(download_error)?
+        .dyn_into::<Response>()
+        .map_err(download_error)?;
+    if !res.ok() {
+        return Err(format!(
+            "Could not download the analysis: {} {}",
+            res.status(),
+            res.status_text()
+        ));
+    }
+    let buf = JsFuture::from(res.array_buffer().map_err(download_error)?)
+        .await
+        .map_err(download_error)?;
+    postcard::from_bytes(&Uint8Array::new(&buf).to_vec())
+        .map_err(|err| format!("Could not read the analysis: {}", err))
+}
+
 #[component]
 pub fn App() -> impl IntoView {
     //let game_setting = GameSetting::try_new_normal_limited(3, 3).unwrap();
-    let (setting_preset, set_setting_preset) = create_signal(SettingPreset::C3x3Limit3);
+    let (setting_preset, set_setting_preset) =
+        create_signal(load_saved_preset().unwrap_or(SettingPreset::C3x3Limit3));
     let game_setting = move || setting_preset().to_game_setting();
     //let game_setting = GameSetting::try_new(3, 4, 3, 4).unwrap();
-    let (game, set_game) = create_signal(Game::new(game_setting()));
+    let saved_or_new_game = move || {
+        load_saved_game()
+            .filter(|game| *game.setting() == game_setting())
+            .unwrap_or_else(|| Game::new(game_setting()))
+    };
+    let (game, set_game) = create_signal(saved_or_new_game());
     //let analysis0 = analyze(game_setting, Default::default(), 1e5 as usize);
     //let analysis1 = move || analyze(game_setting, game().state().clone(), 1e5 as usize);
     //let analysis = create_memo(move |_| analysis0.merge(analysis1()));
@@ -102,29 +228,47 @@ pub fn App() -> impl IntoView {
     let (highlight_last, set_highlight_last) = create_signal(true);
 
     let (downloading, set_downloading) = create_signal(false);
+    let (download_error, set_download_error) = create_signal::<Option<String>>(None);
+    let (take_error, set_take_error) = create_signal::<Option<TakeError>>(None);
 
     create_effect(move |_| {
-        set_game(Game::new(game_setting()));
+        set_game(saved_or_new_game());
         set_analysis(None);
         set_downloading(false);
+        set_download_error(None);
         if matches!(
             setting_preset(),
-            SettingPreset::C3x3Limit3 | SettingPreset::C3x3Limit4 | SettingPreset::C3x3Normal
+            SettingPreset::C3x3Limit3
+                | SettingPreset::C3x3Limit4
+                | SettingPreset::C3x3Limit3Vs4
+                | SettingPreset::C3x3Limit3Misere
+                | SettingPreset::C3x3Limit4Misere
+                | SettingPreset::C3x3TorusLimit3
+                | SettingPreset::C3x3GravityLimit3
+                | SettingPreset::C3x3Normal
         ) {
             set_analysis(Some(Rc::new(analyze(
-                game_setting(),
-                Default::default(),
+                Game::new(game_setting()),
                 usize::MAX,
             ))));
         }
     });
 
+    create_effect(move |_| save_preset(setting_preset()));
+    create_effect(move |_| save_game(&game()));
+    create_effect(move |_| {
+        game.track();
+        set_take_error(None);
+    });
+
     let result_view = move || {
-        let r = game().result();
-        let s = match r {
-            GameResult::FirstWin => "First Win",
-            GameResult::SecondWin => "Second Win",
-            GameResult::Continue => "",
+        let game = game();
+        let s = match (game.result(), game.setting().is_misere()) {
+            (GameResult::FirstWin, false) => "First Win",
+            (GameResult::SecondWin, false) => "Second Win",
+            (GameResult::FirstWin, true) => "First Win (Second made a line)",
+            (GameResult::SecondWin, true) => "Second Win (First made a line)",
+            (GameResult::Continue, _) => "",
         };
         view! { <div>{s}</div> }
     };
@@ -138,9 +282,24 @@ pub fn App() -> impl IntoView {
         view! { <div>Next: {s}</div> }
     };
 
+    let record_view = move || {
+        let s = GameRecord::new(game()).map(|record| record.to_string());
+        view! { <pre>{s}</pre> }
+    };
+
     let board_view = move || {
         let get_game = game;
         let game = game();
+        let best_takes = analysis()
+            .map(|analysis| analysis.best_takes(&game))
+            .unwrap_or_default();
+        let valid_takes = game.valid_takes().collect::<Vec<_>>();
+        let next_vanishing = game.next_vanishing().map(|piece| piece.take);
+        let winning_takes = game
+            .winning_lines()
+            .into_iter()
+            .flat_map(|line| line.takes)
+            .collect::<Vec<_>>();
         let v = game
             .to_cells()
             .into_iter()
@@ -154,40 +313,34 @@ pub fn App() -> impl IntoView {
                             x: x as u8,
                             y: y as u8,
                         };
-                        let is_valid = game.validate_take(take).is_ok();
-                        let analysis = analysis().clone();
-                        let analysis = {
-                            if let Some(analysis) = analysis {
-                                let mut game = game.clone();
-                                if !game.is_finished() && is_valid {
-                                    game.add_take(take);
-                                    let game = game.normalize();
-                                    let analysis = analysis.analysis().get(game.state()).cloned();
-                                    analysis
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        };
+                        // Under gravity a click anywhere in a column drops a piece into it.
+                        let is_valid = valid_takes.iter().any(|valid| {
+                            valid.x == take.x && (game.setting().is_gravity() || valid.y == take.y)
+                        });
+                        let analysis = best_takes
+                            .iter()
+                            .find(|(best_take, _)| *best_take == take)
+                            .map(|(_, analysis)| analysis.clone());
                         let analysis_str = if (game.is_next_first() && show_hint_first())
                             || (!game.is_next_first() && show_hint_second())
                         {
-                            match analysis.unwrap_or_default() {
-                                GameAnalysis::Winning(t) => format!("<L{}>", t),
-                                GameAnalysis::Losing(t) => format!("<W{}>", t),
-                                GameAnalysis::Neutral => " ".to_string(),
+                            match analysis {
+                                Some(GameAnalysis::Winning(t)) => format!("<W{}>", t),
+                                Some(GameAnalysis::Losing(t)) => format!("<L{}>", t),
+                                Some(GameAnalysis::Draw) => "<D>".to_string(),
+                                Some(GameAnalysis::Unknown) => "<?>".to_string(),
+                                None => " ".to_string(),
                             }
                         } else {
                             "".to_string()
                         };
+                        let is_next_vanishing = next_vanishing == Some(take);
                         let format_piece = |is_first: bool, i: usize| {
                             let piece = if is_first { "O" } else { "X" };
                             let num = match mode() {
                                 ShowMode::Nothing => "".to_string(),
                                 ShowMode::Last => {
-                                    if i == 0 && (game.is_next_first() == is_first) {
+                                    if is_next_vanishing {
                                         i.to_string()
                                     } else {
                                         "".to_string()
@@ -203,6 +356,7 @@ pub fn App() -> impl IntoView {
                             CellView::Second(i) => format_piece(false, i),
                         };
                         let is_last = game.is_last_take(take);
+                        let in_winning_line = winning_takes.contains(&take);
                         let base_color = match cell {
                             CellView::First(_) => "blue",
                             CellView::Second(_) => "red",
@@ -215,6 +369,11 @@ pub fn App() -> impl IntoView {
                                 style:font-size="22px"
                                 style:font-weight="bold"
                                 style:color=base_color
+                                style:background-color=if in_winning_line {
+                                    Some("gold")
+                                } else {
+                                    None
+                                }
                                 style:border=if is_last && highlight_last() {
                                     Some("5px solid")
                                 } else {
@@ -225,13 +384,17 @@ pub fn App() -> impl IntoView {
                                 } else {
                                     None
                                 }
-                                disabled=game.is_finished() || !is_valid
+                                style:opacity=if is_valid { None } else { Some("0.5") }
+                                disabled=game.is_finished()
                                 on:click=move |_ev| {
-                                    if get_game().validate_take(take).is_ok() {
-                                        set_game
-                                            .update(|game| {
-                                                game.add_take(take);
-                                            });
+                                    match get_game().validate_take(take) {
+                                        Ok(()) => {
+                                            set_game
+                                                .update(|game| {
+                                                    game.add_take(take);
+                                                });
+                                        }
+                                        Err(e) => set_take_error(Some(e)),
                                     }
                                 }
                             >
@@ -248,41 +411,41 @@ pub fn App() -> impl IntoView {
         view! { <div>{v}</div> }
     };
 
-    let download_analysis = move || {
+    let download_analysis = move |url: &'static str| {
         move || {
             set_downloading(true);
-            let p = future_to_promise(async move {
-                let window = window().unwrap();
-                let res = JsFuture::from(window.fetch_with_str("/analyzed_3x4_4.bin"))
-                    .await
-                    .unwrap();
-                let res = res.dyn_into::<Response>().unwrap();
-                let buf = JsFuture::from(res.array_buffer().unwrap()).await.unwrap();
-                let buf = Uint8Array::new(&buf);
-                let buf = buf.to_vec();
-                let ad = postcard::from_bytes::<AnalysisDictionary>(&buf).unwrap();
-                set_analysis(Some(Rc::new(ad)));
-                Ok(JsValue::NULL)
-            });
-            let _ = p.catch(&Closure::once(move |err: JsValue| {
-                logging::log!("err {:?}", err);
+            set_download_error(None);
+            spawn_local(async move {
+                match fetch_analysis(url).await {
+                    Ok(ad) if *ad.setting() == game_setting() => set_analysis(Some(Rc::new(ad))),
+                    Ok(ad) => set_download_error(Some(format!(
+                        "The analysis is for {}, not for {}",
+                        ad.setting(),
+                        game_setting()
+                    ))),
+                    Err(err) => set_download_error(Some(err)),
+                }
                 set_downloading(false);
-            }));
+            });
         }
     };
 
     let analyzed_view = move || {
         if analysis().is_none() {
+            let Some(url) = setting_preset().analysis_url() else {
+                return view! { <div>{"No analysis is available for this setting"}</div> };
+            };
             view! {
                 <div>
                     <button
                         disabled=downloading
                         on:click=move |_ev| {
-                            download_analysis()();
+                            download_analysis(url)();
                         }
                     >
                         {"Download Analysis"}
                     </button>
+                    <div>{download_error}</div>
                 </div>
             }
         } else {
@@ -292,36 +455,13 @@ pub fn App() -> impl IntoView {
                         <button
                             disabled=move || game().is_finished() || analysis().is_none()
                             on:click=move |_ev| {
-                                let analysis = analysis().unwrap();
-                                let a = analysis.analysis();
-                                let mut best_way = GameAnalysis::min();
-                                let mut best_takes = vec![];
-                                for y in 0..game_setting().board_height() {
-                                    for x in 0..game_setting().board_width() {
-                                        let take = Take { x: x as u8, y: y as u8 };
-                                        let game = game();
-                                        if game.validate_take(take).is_ok() {
-                                            let mut game = game;
-                                            game.add_take(take);
-                                            let game = game.normalize();
-                                            let analysis = a
-                                                .get(game.state())
-                                                .cloned()
-                                                .unwrap_or_default();
-                                            match analysis.cmp(&best_way) {
-                                                std::cmp::Ordering::Less => {}
-                                                std::cmp::Ordering::Equal => {
-                                                    best_takes.push(take);
-                                                }
-                                                std::cmp::Ordering::Greater => {
-                                                    best_way = analysis;
-                                                    best_takes.clear();
-                                                    best_takes.push(take);
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
+                                let best_takes = analysis().unwrap().best_takes(&game());
+                                let best_way = best_takes[0].1.clone();
+                                let best_takes = best_takes
+                                    .into_iter()
+                                    .filter(|(_, analysis)| *analysis == best_way)
+                                    .map(|(take, _)| take)
+                                    .collect::<Vec<_>>();
                                 let mut seed = [0; 32];
                                 window()
                                     .unwrap()
@@ -385,6 +525,8 @@ pub fn App() -> impl IntoView {
             <div>{board_view}</div>
             <div>{next_player_view}</div>
             <div>{result_view}</div>
+            <div>{move || take_error().map(|e| e.to_string())}</div>
+            <div>{record_view}</div>
             <div>
                 <button on:click=move |_ev| {
                     set_game
@@ -392,6 +534,28 @@ pub fn App() -> impl IntoView {
                             *game = Game::new(game_setting());
                         });
                 }>{"Reset"}</button>
+                <button
+                    disabled=move || !game().can_undo()
+                    on:click=move |_ev| {
+                        set_game
+                            .update(|game| {
+                                game.undo();
+                            });
+                    }
+                >
+                    {"Undo"}
+                </button>
+                <button
+                    disabled=move || !game().can_redo()
+                    on:click=move |_ev| {
+                        set_game
+                            .update(|game| {
+                                game.redo();
+                            });
+                    }
+                >
+                    {"Redo"}
+                </button>
             </div>
             {analyzed_view}
             <div>
@@ -1,4 +1,6 @@
 use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Take {
@@ -12,14 +14,281 @@ impl Take {
     pub fn y(&self) -> usize {
         self.y as usize
     }
+
+    pub fn apply(&self, transform: impl Into<Transform>, setting: &GameSetting) -> Take {
+        let Transform { symmetry, dx, dy } = transform.into();
+        let (x, y) = (self.x, self.y);
+        let (width, height) = (setting.board_width, setting.board_height);
+        if symmetry.requires_square() && width != height {
+            panic!("Board should be square");
+        }
+        let (x, y) = match symmetry {
+            Symmetry::Identity => (x, y),
+            Symmetry::Rotate90 => (height - y - 1, x),
+            Symmetry::Rotate180 => (width - x - 1, height - y - 1),
+            Symmetry::Rotate270 => (y, width - x - 1),
+            Symmetry::MirrorX => (width - x - 1, y),
+            Symmetry::MirrorY => (x, height - y - 1),
+            Symmetry::MirrorDiagonal => (y, x),
+            Symmetry::MirrorAntiDiagonal => (height - y - 1, width - x - 1),
+        };
+        Take {
+            x: (x + dx % width) % width,
+            y: (y + dy % height) % height,
+        }
+    }
+
+    pub fn inverse(&self, transform: impl Into<Transform>, setting: &GameSetting) -> Take {
+        let Transform { symmetry, dx, dy } = transform.into();
+        let (width, height) = (setting.board_width, setting.board_height);
+        let shifted_back = Take {
+            x: (self.x + width - dx % width) % width,
+            y: (self.y + height - dy % height) % height,
+        };
+        shifted_back.apply(symmetry.inverse(), setting)
+    }
+}
+/// Writes the take as a column letter from `a` and a row number from 1 counted from the top,
+/// e.g. `b3` for `Take { x: 1, y: 2 }`.
+impl fmt::Display for Take {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let column = char::from_u32('a' as u32 + self.x as u32).unwrap();
+        write!(f, "{}{}", column, self.y as usize + 1)
+    }
+}
+impl FromStr for Take {
+    type Err = ParseTakeError;
+
+    fn from_str(s: &str) -> Result<Take, ParseTakeError> {
+        let mut chars = s.chars();
+        let column = chars.next().ok_or(ParseTakeError::Empty)?;
+        if !('a'..='k').contains(&column) {
+            return Err(ParseTakeError::InvalidColumn(column));
+        }
+        let row = chars.as_str();
+        match row.parse::<u8>() {
+            Ok(y @ 1..=11) if row.bytes().all(|b| b.is_ascii_digit()) => Ok(Take {
+                x: column as u8 - b'a',
+                y: y - 1,
+            }),
+            _ => Err(ParseTakeError::InvalidRow(row.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseTakeError {
+    Empty,
+    InvalidColumn(char),
+    InvalidRow(String),
+}
+impl fmt::Display for ParseTakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseTakeError::Empty => write!(f, "Take should not be empty"),
+            ParseTakeError::InvalidColumn(column) => {
+                write!(f, "Column should be between a and k, but {:?}", column)
+            }
+            ParseTakeError::InvalidRow(row) => {
+                write!(f, "Row should be between 1 and 11, but {:?}", row)
+            }
+        }
+    }
+}
+impl std::error::Error for ParseTakeError {}
+
+/// Takes written one after another, separated by spaces, e.g. `b2 a1 c3`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MoveList(pub Vec<Take>);
+impl fmt::Display for MoveList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, take) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", take)?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for MoveList {
+    type Err = ParseTakeError;
+
+    fn from_str(s: &str) -> Result<MoveList, ParseTakeError> {
+        s.split_whitespace()
+            .map(Take::from_str)
+            .collect::<Result<_, _>>()
+            .map(MoveList)
+    }
+}
+
+/// Rotations and mirrors of the board. `Rotate90` is the same as `BoardState::rotate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Symmetry {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    MirrorX,
+    MirrorY,
+    MirrorDiagonal,
+    MirrorAntiDiagonal,
+}
+impl Symmetry {
+    /// The symmetries of the board: the dihedral group for square boards, and only the
+    /// identity, both mirrors and the half turn otherwise.
+    pub fn all(setting: &GameSetting) -> &'static [Symmetry] {
+        if setting.board_height() == setting.board_width() {
+            &[
+                Symmetry::Identity,
+                Symmetry::Rotate90,
+                Symmetry::Rotate180,
+                Symmetry::Rotate270,
+                Symmetry::MirrorX,
+                Symmetry::MirrorY,
+                Symmetry::MirrorDiagonal,
+                Symmetry::MirrorAntiDiagonal,
+            ]
+        } else {
+            &[
+                Symmetry::Identity,
+                Symmetry::MirrorX,
+                Symmetry::MirrorY,
+                Symmetry::Rotate180,
+            ]
+        }
+    }
+
+    pub fn inverse(self) -> Symmetry {
+        match self {
+            Symmetry::Rotate90 => Symmetry::Rotate270,
+            Symmetry::Rotate270 => Symmetry::Rotate90,
+            symmetry => symmetry,
+        }
+    }
+
+    fn requires_square(self) -> bool {
+        matches!(
+            self,
+            Symmetry::Rotate90
+                | Symmetry::Rotate270
+                | Symmetry::MirrorDiagonal
+                | Symmetry::MirrorAntiDiagonal
+        )
+    }
+}
+
+/// A symmetry followed by a shift of every cell `dx` columns to the right and `dy` rows down,
+/// wrapping around the edges. Shifts other than zero are symmetries only of toroidal boards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Transform {
+    pub symmetry: Symmetry,
+    pub dx: u8,
+    pub dy: u8,
+}
+impl From<Symmetry> for Transform {
+    fn from(symmetry: Symmetry) -> Transform {
+        Transform {
+            symmetry,
+            dx: 0,
+            dy: 0,
+        }
+    }
+}
+impl Transform {
+    /// The symmetries of `Symmetry::all`, each followed by every shift if the board is a torus.
+    /// Under gravity the bottom stays where it is, so only the identity and the left-right
+    /// mirror are left, with the shifts along the rows. The identity comes first.
+    pub fn all(setting: &GameSetting) -> Vec<Transform> {
+        Transform::of(setting, Symmetry::all(setting))
+    }
+
+    /// Each of `symmetries` followed by every shift the setting allows, as in `all`.
+    pub fn of(setting: &GameSetting, symmetries: &[Symmetry]) -> Vec<Transform> {
+        let shifts = if setting.is_torus() {
+            let rows = if setting.is_gravity() {
+                1
+            } else {
+                setting.board_height
+            };
+            (0..rows)
+                .flat_map(|dy| (0..setting.board_width).map(move |dx| (dx, dy)))
+                .collect()
+        } else {
+            vec![(0, 0)]
+        };
+        shifts
+            .into_iter()
+            .flat_map(|(dx, dy)| {
+                symmetries
+                    .iter()
+                    .filter(|&&symmetry| {
+                        !setting.is_gravity()
+                            || matches!(symmetry, Symmetry::Identity | Symmetry::MirrorX)
+                    })
+                    .map(move |&symmetry| Transform { symmetry, dx, dy })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingError {
+    BoardHeightOutOfRange(usize),
+    BoardWidthOutOfRange(usize),
+    GoalOutOfRange { goal: usize, max: usize },
+    PieceLimitOutOfRange(usize),
+}
+impl fmt::Display for SettingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingError::BoardHeightOutOfRange(height) => {
+                write!(f, "Board height should be between 1 and 11, but {}", height)
+            }
+            SettingError::BoardWidthOutOfRange(width) => {
+                write!(f, "Board width should be between 1 and 11, but {}", width)
+            }
+            SettingError::GoalOutOfRange { goal, max } => write!(
+                f,
+                "Goal should be between 1 and the shorter board side ({}), but {}",
+                max, goal
+            ),
+            SettingError::PieceLimitOutOfRange(piece_limit) => write!(
+                f,
+                "Piece limit should be between 1 and 127, but {}",
+                piece_limit
+            ),
+        }
+    }
 }
+impl std::error::Error for SettingError {}
 
+/// Serialized as its text form, so that adding options does not change the format, and
+/// checked when deserialized.
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub struct GameSetting {
     board_height: u8,
     board_width: u8,
     goal: u8,
     piece_limit: u8,
+    second_piece_limit: u8,
+    misere: bool,
+    torus: bool,
+    gravity: bool,
+}
+
+impl From<GameSetting> for String {
+    fn from(setting: GameSetting) -> String {
+        setting.to_string()
+    }
+}
+impl TryFrom<String> for GameSetting {
+    type Error = ParseSettingError;
+
+    fn try_from(s: String) -> Result<GameSetting, ParseSettingError> {
+        s.parse()
+    }
 }
 impl GameSetting {
     pub fn try_new(
@@ -27,48 +296,54 @@ impl GameSetting {
         board_width: usize,
         goal: usize,
         piece_limit: usize,
-    ) -> Result<GameSetting, String> {
-        if board_height == 0 {
-            return Err("Board height should be greater than 0".to_string());
-        }
-        if board_height > 11 {
-            return Err("Board height should be less than or equal to 11".to_string());
-        }
-        if board_width == 0 {
-            return Err("Board width should be greater than 0".to_string());
-        }
-        if board_width > 11 {
-            return Err("Board width should be less than or equal to 11".to_string());
-        }
-        if goal == 0 {
-            return Err("Goal should be greater than 0".to_string());
-        }
-        if goal > board_height {
-            return Err("Goal should be less than or equal to board height".to_string());
+    ) -> Result<GameSetting, SettingError> {
+        GameSetting::try_new_asymmetric(board_height, board_width, goal, piece_limit, piece_limit)
+    }
+    /// Setting where the first player keeps up to `piece_limit` pieces and the second player
+    /// up to `second_piece_limit`.
+    pub fn try_new_asymmetric(
+        board_height: usize,
+        board_width: usize,
+        goal: usize,
+        piece_limit: usize,
+        second_piece_limit: usize,
+    ) -> Result<GameSetting, SettingError> {
+        if !(1..=11).contains(&board_height) {
+            return Err(SettingError::BoardHeightOutOfRange(board_height));
         }
-        if goal > board_width {
-            return Err("Goal should be less than or equal to board width".to_string());
+        if !(1..=11).contains(&board_width) {
+            return Err(SettingError::BoardWidthOutOfRange(board_width));
         }
-        if piece_limit == 0 {
-            return Err("Piece limit should be greater than 0".to_string());
+        let max_goal = board_height.min(board_width);
+        if !(1..=max_goal).contains(&goal) {
+            return Err(SettingError::GoalOutOfRange {
+                goal,
+                max: max_goal,
+            });
         }
-        if piece_limit > 127 {
-            return Err("Piece limit should be less than or equal to 127".to_string());
+        for piece_limit in [piece_limit, second_piece_limit] {
+            if !(1..=127).contains(&piece_limit) {
+                return Err(SettingError::PieceLimitOutOfRange(piece_limit));
+            }
         }
         Ok(GameSetting {
             board_height: board_height as u8,
             board_width: board_width as u8,
             goal: goal as u8,
             piece_limit: piece_limit as u8,
+            second_piece_limit: second_piece_limit as u8,
+            misere: false,
+            torus: false,
+            gravity: false,
         })
     }
     pub fn try_new_normal_limited(
         board_size: usize,
         piece_limit: usize,
-    ) -> Result<GameSetting, String> {
+    ) -> Result<GameSetting, SettingError> {
         GameSetting::try_new(board_size, board_size, board_size, piece_limit)
     }
-    pub fn try_new_normal(board_size: usize) -> Result<GameSetting, String> {
+    pub fn try_new_normal(board_size: usize) -> Result<GameSetting, SettingError> {
         GameSetting::try_new(board_size, board_size, board_size, board_size * board_size)
     }
     pub fn board_height(&self) -> usize {
@@ -80,11 +355,252 @@ impl GameSetting {
     pub fn goal(&self) -> usize {
         self.goal as usize
     }
+    /// Piece limit of the first player, which is also the second player's unless the setting
+    /// is asymmetric.
     pub fn piece_limit(&self) -> usize {
         self.piece_limit as usize
     }
+    pub fn second_piece_limit(&self) -> usize {
+        self.second_piece_limit as usize
+    }
+    pub fn player_piece_limit(&self, first: bool) -> usize {
+        if first {
+            self.piece_limit()
+        } else {
+            self.second_piece_limit()
+        }
+    }
+    pub fn is_asymmetric(&self) -> bool {
+        self.piece_limit != self.second_piece_limit
+    }
+    /// The same setting, where completing a line loses instead of winning if `misere` is true.
+    pub fn with_misere(self, misere: bool) -> GameSetting {
+        GameSetting { misere, ..self }
+    }
+    pub fn is_misere(&self) -> bool {
+        self.misere
+    }
+    /// The same setting, where rows, columns and diagonals wrap around the edges of the board
+    /// if `torus` is true.
+    pub fn with_torus(self, torus: bool) -> GameSetting {
+        GameSetting { torus, ..self }
+    }
+    pub fn is_torus(&self) -> bool {
+        self.torus
+    }
+    /// The same setting, where a take only chooses a column and the piece falls to the lowest
+    /// empty cell of it if `gravity` is true. Pieces above a vanished piece fall down as well.
+    pub fn with_gravity(self, gravity: bool) -> GameSetting {
+        GameSetting { gravity, ..self }
+    }
+    pub fn is_gravity(&self) -> bool {
+        self.gravity
+    }
+
+    fn cell(&self, take: Take) -> usize {
+        take.y() * self.board_width() + take.x()
+    }
+    fn take(&self, cell: usize) -> Take {
+        Take {
+            x: (cell % self.board_width()) as u8,
+            y: (cell / self.board_width()) as u8,
+        }
+    }
+    fn takes_in(&self, mask: u128) -> Vec<Take> {
+        (0..self.board_height() * self.board_width())
+            .filter(|&cell| mask & (1 << cell) != 0)
+            .map(|cell| self.take(cell))
+            .collect()
+    }
+    /// Most pieces a player can have on the board at once.
+    fn ring_capacity(&self, first: bool) -> usize {
+        self.player_piece_limit(first)
+            .min(self.board_height() * self.board_width())
+    }
+    /// Most pieces that can be on the board at once.
+    fn max_pieces(&self) -> usize {
+        (self.piece_limit() + self.second_piece_limit())
+            .min(self.board_height() * self.board_width())
+    }
+    /// Whether some number of steps leaves `len` pieces with `is_next_first` to move.
+    fn has_steps(&self, len: usize, is_next_first: bool) -> bool {
+        let owners = self.owners(len, is_next_first);
+        let first = owners.iter().filter(|&&first| first).count();
+        owners.len() == len
+            && (0..=2 * len + 2)
+                .filter(|steps| (steps % 2 == 0) == is_next_first)
+                .any(|steps: usize| {
+                    steps.div_ceil(2).min(self.player_piece_limit(true)) == first
+                        && (steps / 2).min(self.player_piece_limit(false)) == len - first
+                })
+    }
+    /// Owners of `len` pieces from the oldest, `true` for the first player, when
+    /// `is_next_first` tells who moves next. Going back from the newest piece, the owners
+    /// alternate until a player has as many pieces as their limit; older takes of that player
+    /// have vanished, so the remaining pieces are the other player's.
+    fn owners(&self, len: usize, is_next_first: bool) -> Vec<bool> {
+        let mut owners = vec![];
+        let mut counts = [0, 0];
+        let mut first = !is_next_first;
+        while owners.len() < len {
+            let count = &mut counts[player_index(first)];
+            if *count < self.player_piece_limit(first) {
+                *count += 1;
+                owners.push(first);
+            } else if counts[player_index(!first)] >= self.player_piece_limit(!first) {
+                break;
+            }
+            first = !first;
+        }
+        owners.reverse();
+        owners
+    }
+    /// Names of the options turned on, as the text forms of the setting write them.
+    fn options(&self) -> Vec<&'static str> {
+        let mut options = vec![];
+        if self.misere {
+            options.push("misere");
+        }
+        if self.torus {
+            options.push("torus");
+        }
+        if self.gravity {
+            options.push("gravity");
+        }
+        options
+    }
+    /// Returns `None` if there is no option of the name.
+    fn with_option(self, name: &str) -> Option<GameSetting> {
+        match name {
+            "misere" => Some(self.with_misere(true)),
+            "torus" => Some(self.with_torus(true)),
+            "gravity" => Some(self.with_gravity(true)),
+            _ => None,
+        }
+    }
+    fn piece_limits_text(&self) -> String {
+        if self.is_asymmetric() {
+            format!("{}/{}", self.piece_limit, self.second_piece_limit)
+        } else {
+            self.piece_limit.to_string()
+        }
+    }
+
+    fn lines(&self) -> Vec<(u128, LineDirection)> {
+        let goal = self.goal() as isize;
+        let mut lines = vec![];
+        for y in 0..self.board_height() as isize {
+            for x in 0..self.board_width() as isize {
+                for direction in [
+                    LineDirection::Row,
+                    LineDirection::Column,
+                    LineDirection::Diagonal,
+                    LineDirection::AntiDiagonal,
+                ] {
+                    let (dx, dy) = direction.step();
+                    let (end_x, end_y) = (x + dx * (goal - 1), y + dy * (goal - 1));
+                    if end_x < 0
+                        || end_x >= self.board_width() as isize
+                        || end_y >= self.board_height() as isize
+                    {
+                        continue;
+                    }
+                    let line = (0..goal).fold(0, |line, i| {
+                        let take = Take {
+                            x: (x + dx * i) as u8,
+                            y: (y + dy * i) as u8,
+                        };
+                        line | 1 << self.cell(take)
+                    });
+                    // With a goal of 1 every direction gives the same line.
+                    if lines.iter().all(|&(other, _)| other != line) {
+                        lines.push((line, direction));
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Writes the setting as `{height}x{width}:{goal}:{piece_limit}`, e.g. `3x3:3:3`. An
+/// asymmetric setting writes both piece limits, the first player's first, e.g. `3x3:3:3/4`,
+/// and options follow, each after a colon, e.g. `3x3:3:3:misere`.
+impl fmt::Display for GameSetting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{}:{}:{}",
+            self.board_height,
+            self.board_width,
+            self.goal,
+            self.piece_limits_text()
+        )?;
+        for option in self.options() {
+            write!(f, ":{}", option)?;
+        }
+        Ok(())
+    }
+}
+impl FromStr for GameSetting {
+    type Err = ParseSettingError;
+
+    fn from_str(s: &str) -> Result<GameSetting, ParseSettingError> {
+        let invalid = || ParseSettingError::InvalidFormat(s.to_string());
+        let mut parts = s.split(':');
+        let (Some(size), Some(goal), Some(piece_limit)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let (board_height, board_width) = size.split_once('x').ok_or_else(invalid)?;
+        let number = |s: &str| s.parse::<usize>().map_err(|_| invalid());
+        let (piece_limit, second_piece_limit) =
+            parse_piece_limits(piece_limit).ok_or_else(invalid)?;
+        let setting = GameSetting::try_new_asymmetric(
+            number(board_height)?,
+            number(board_width)?,
+            number(goal)?,
+            piece_limit,
+            second_piece_limit,
+        )
+        .map_err(ParseSettingError::Setting)?;
+        parts.try_fold(setting, |setting, option| {
+            setting.with_option(option).ok_or_else(invalid)
+        })
+    }
 }
 
+/// Reads a piece limit shared by both players, or the two limits separated by `/`.
+fn parse_piece_limits(s: &str) -> Option<(usize, usize)> {
+    match s.split_once('/') {
+        Some((first, second)) => Some((first.parse().ok()?, second.parse().ok()?)),
+        None => {
+            let piece_limit = s.parse().ok()?;
+            Some((piece_limit, piece_limit))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseSettingError {
+    InvalidFormat(String),
+    Setting(SettingError),
+}
+impl fmt::Display for ParseSettingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseSettingError::InvalidFormat(s) => write!(
+                f,
+                "Setting should be height x width:goal:piece_limit[:option] like 3x3:3:3, but {:?}",
+                s
+            ),
+            ParseSettingError::Setting(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for ParseSettingError {}
+
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct BoardState {
     takes: Vec<Take>,
@@ -126,28 +642,149 @@ impl BoardState {
         BoardState { takes: new_takes }
     }
 
+    pub fn apply(&self, transform: impl Into<Transform>, setting: &GameSetting) -> BoardState {
+        let transform = transform.into();
+        BoardState {
+            takes: self
+                .takes
+                .iter()
+                .map(|take| take.apply(transform, setting))
+                .collect(),
+        }
+    }
+
+    pub fn inverse(&self, transform: impl Into<Transform>, setting: &GameSetting) -> BoardState {
+        let transform = transform.into();
+        BoardState {
+            takes: self
+                .takes
+                .iter()
+                .map(|take| take.inverse(transform, setting))
+                .collect(),
+        }
+    }
+
     pub fn normalized(&self, setting: &GameSetting) -> BoardState {
-        let isotopes = if setting.board_height() == setting.board_width() {
-            let mut v = vec![self.clone()];
-            for _ in 0..3 {
-                v.push(v.last().unwrap().rotate(setting));
+        self.normalized_with_symmetry(setting).0
+    }
+
+    /// Returns the normalized state together with the symmetry that maps this state onto it.
+    pub fn normalized_with_symmetry(&self, setting: &GameSetting) -> (BoardState, Transform) {
+        self.normalized_among(setting, &Transform::all(setting))
+    }
+
+    fn normalized_among(
+        &self,
+        setting: &GameSetting,
+        symmetries: &[Transform],
+    ) -> (BoardState, Transform) {
+        symmetries
+            .iter()
+            .map(|&symmetry| (self.apply(symmetry, setting), symmetry))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .unwrap()
+    }
+
+    pub fn is_normalized(&self, setting: &GameSetting) -> bool {
+        self == &self.normalized(setting)
+    }
+
+    /// Rebuilds the state shown by `to_cells`. Returns `None` if the cells do not match the
+    /// size of the board or their numbers cannot be put in order.
+    fn from_cells(
+        setting: &GameSetting,
+        cells: &[Vec<CellView>],
+        steps_taken: usize,
+    ) -> Option<BoardState> {
+        if cells.len() != setting.board_height()
+            || cells.iter().any(|row| row.len() != setting.board_width())
+        {
+            return None;
+        }
+        let mut pieces = [vec![], vec![]];
+        for (y, row) in cells.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                let (first, rest) = match *cell {
+                    CellView::None => continue,
+                    CellView::First(rest) => (true, rest),
+                    CellView::Second(rest) => (false, rest),
+                };
+                let take = Take {
+                    x: x as u8,
+                    y: y as u8,
+                };
+                pieces[player_index(first)].push((rest, take));
+            }
+        }
+        let len = pieces[0].len() + pieces[1].len();
+        let owners = setting.owners(len, steps_taken % 2 == 0);
+        let mut queues = [vec![], vec![]];
+        for first in [true, false] {
+            let pieces = &pieces[player_index(first)];
+            let count = owners.iter().filter(|&&owner| owner == first).count();
+            if pieces.len() != count {
+                return None;
             }
-            v.push(v.last().unwrap().mirror_x(setting));
-            for _ in 0..3 {
-                v.push(v.last().unwrap().rotate(setting));
+            // `rest` of the j-th oldest piece of a player is `limit - count + j`.
+            let offset = setting.player_piece_limit(first) - count;
+            let mut queue = vec![None; count];
+            for &(rest, take) in pieces {
+                let j = rest.checked_sub(offset).filter(|&j| j < count)?;
+                if queue[j].replace(take).is_some() {
+                    return None;
+                }
             }
-            v
-        } else {
-            [self.clone(), self.mirror_x(setting)]
+            queues[player_index(first)] = queue;
+        }
+        let mut next = [0, 0];
+        Some(BoardState {
+            takes: owners
                 .into_iter()
-                .flat_map(|state| [state.clone(), state.mirror_y(setting)].into_iter())
-                .collect::<Vec<_>>()
-        };
-        isotopes.into_iter().min().unwrap()
+                .map(|first| {
+                    let i = &mut next[player_index(first)];
+                    *i += 1;
+                    queues[player_index(first)][*i - 1]
+                })
+                .collect::<Option<_>>()?,
+        })
     }
 
-    pub fn is_normalized(&self, setting: &GameSetting) -> bool {
-        self == &self.normalized(setting)
+    fn verify(&self, setting: &GameSetting) -> Result<(), StateError> {
+        // takes len should be up to the sum of the piece limits
+        if self.takes.len() > setting.piece_limit() + setting.second_piece_limit() {
+            return Err(StateError::TooManyTakes {
+                limit: setting.piece_limit() + setting.second_piece_limit(),
+                len: self.takes.len(),
+            });
+        }
+        // should not take the same cell
+        {
+            let mut set = std::collections::HashSet::new();
+            for take in self.takes.iter() {
+                if !set.insert(take) {
+                    return Err(StateError::DuplicateCell(*take));
+                }
+            }
+        }
+        // should not take out of board
+        for take in self.takes.iter() {
+            if take.x() >= setting.board_width() || take.y() >= setting.board_height() {
+                return Err(StateError::OutOfBoard(*take));
+            }
+        }
+        // should rest on the bottom or on another piece under gravity
+        if setting.is_gravity() {
+            for take in self.takes.iter() {
+                let below = Take {
+                    x: take.x,
+                    y: take.y + 1,
+                };
+                if below.y() < setting.board_height() && !self.takes.contains(&below) {
+                    return Err(StateError::Floating(*take));
+                }
+            }
+        }
+        Ok(())
     }
 }
 impl Eq for BoardState {}
@@ -183,281 +820,2072 @@ impl Hash for BoardState {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum CellView {
-    None,
-    First(usize),
-    Second(usize),
+/// Dense numbering of the `AnalysisKey`s of a setting. The states are ordered sequences of
+/// distinct cells with at most as many takes as the two piece limits together, including ones
+/// that leave pieces floating under gravity. Shorter sequences come first, and sequences of
+/// the same length are ordered lexicographically by cell. Each state is followed by itself
+/// with the second player to move.
+#[derive(Debug, Clone)]
+pub struct StateIndexer {
+    setting: GameSetting,
+    /// `offsets[k]` is the number of states with fewer than `k` takes.
+    offsets: Vec<u64>,
+    /// `permutations[n][k]` is the number of ordered choices of `k` out of `n` cells.
+    permutations: Vec<Vec<u64>>,
 }
-impl CellView {
-    pub fn is_first(&self) -> bool {
-        matches!(self, CellView::First(_))
+impl StateIndexer {
+    /// Returns `None` if the number of keys does not fit in `u64`.
+    pub fn try_new(setting: GameSetting) -> Option<StateIndexer> {
+        let cells = setting.board_height() * setting.board_width();
+        let max_len = setting.max_pieces();
+        let mut permutations = vec![];
+        for n in 0..=cells {
+            let mut row = vec![1u64];
+            for k in 1..=n.min(max_len) {
+                row.push(row[k - 1].checked_mul((n - k + 1) as u64)?);
+            }
+            permutations.push(row);
+        }
+        let mut offsets = vec![0u64];
+        for k in 0..=max_len {
+            offsets.push(offsets[k].checked_add(permutations[cells][k])?);
+        }
+        offsets.last()?.checked_mul(2)?;
+        Some(StateIndexer {
+            setting,
+            offsets,
+            permutations,
+        })
     }
-    pub fn is_second(&self) -> bool {
-        matches!(self, CellView::Second(_))
+
+    pub fn setting(&self) -> &GameSetting {
+        &self.setting
     }
-    pub fn is_none(&self) -> bool {
-        matches!(self, CellView::None)
+
+    pub fn len(&self) -> u64 {
+        self.offsets.last().unwrap() * 2
     }
-}
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum GameResult {
-    FirstWin,
-    SecondWin,
-    Continue,
-}
-impl GameResult {
-    pub fn is_win(&self) -> bool {
-        matches!(self, GameResult::FirstWin | GameResult::SecondWin)
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `None` if the state has too many takes, or takes out of the board or on the
+    /// same cell.
+    pub fn rank(&self, key: &AnalysisKey) -> Option<u64> {
+        let takes = &key.state.takes;
+        let len = takes.len();
+        if len > self.setting.max_pieces() {
+            return None;
+        }
+        let mut used = 0u128;
+        for &take in takes {
+            if take.x() >= self.setting.board_width() || take.y() >= self.setting.board_height() {
+                return None;
+            }
+            let cell = self.setting.cell(take);
+            if used & (1 << cell) != 0 {
+                return None;
+            }
+            used |= 1 << cell;
+        }
+        let cells = takes.iter().map(|&take| self.setting.cell(take));
+        Some(self.rank_cells(cells, len) * 2 + !key.is_next_first as u64)
+    }
+
+    /// Rank of the state made of `len` distinct cells, without the side to move.
+    fn rank_cells(&self, cells: impl Iterator<Item = usize>, len: usize) -> u64 {
+        let board_cells = self.setting.board_height() * self.setting.board_width();
+        let mut used = 0u128;
+        let mut index = self.offsets[len];
+        for (i, cell) in cells.enumerate() {
+            let smaller_unused = cell - (used & ((1 << cell) - 1)).count_ones() as usize;
+            index += smaller_unused as u64 * self.permutations[board_cells - 1 - i][len - 1 - i];
+            used |= 1 << cell;
+        }
+        index
+    }
+
+    /// Returns `None` if the index is out of range.
+    pub fn unrank(&self, index: u64) -> Option<AnalysisKey> {
+        if index >= self.len() {
+            return None;
+        }
+        let is_next_first = index % 2 == 0;
+        let index = index / 2;
+        let cells = self.setting.board_height() * self.setting.board_width();
+        let len = self.offsets.partition_point(|&offset| offset <= index) - 1;
+        let mut rest = index - self.offsets[len];
+        let mut used = 0u128;
+        let mut takes = vec![];
+        for i in 0..len {
+            let block = self.permutations[cells - 1 - i][len - 1 - i];
+            let smaller_unused = (rest / block) as usize;
+            rest %= block;
+            let cell = (0..cells)
+                .filter(|&cell| used & (1 << cell) == 0)
+                .nth(smaller_unused)
+                .unwrap();
+            used |= 1 << cell;
+            takes.push(self.setting.take(cell));
+        }
+        Some(AnalysisKey {
+            state: BoardState { takes },
+            is_next_first,
+        })
     }
 }
 
+/// Dense numbering of the positions of a setting up to symmetry. Each position is numbered by
+/// its normalized `AnalysisKey`, in the order of their `StateIndexer` ranks. Only keys whose
+/// piece counts some number of steps gives are numbered, and under gravity only those without
+/// floating pieces. Building it goes through the states of the `StateIndexer` in order,
+/// skipping those whose first takes are already not normalized.
 #[derive(Debug, Clone)]
-pub struct Game {
-    setting: GameSetting,
-    state: BoardState,
-    steps_taken: usize,
+pub struct CanonicalStateIndexer {
+    indexer: StateIndexer,
+    /// Cells each cell is mapped to, indexed by symmetry and then by cell.
+    maps: Vec<Vec<usize>>,
+    /// `StateIndexer` ranks of the normalized keys, in increasing order.
+    ranks: Vec<u64>,
 }
-impl Game {
-    pub fn add_take(&mut self, take: Take) {
-        assert!(!self.is_finished(), "Game already finished");
-        self.state.takes = self
+impl CanonicalStateIndexer {
+    /// Returns `None` if the keys of the setting cannot be numbered by a `StateIndexer`.
+    pub fn try_new(setting: GameSetting) -> Option<CanonicalStateIndexer> {
+        CanonicalStateIndexer::try_with_symmetries(setting, &Transform::all(&setting))
+    }
+
+    fn try_with_symmetries(
+        setting: GameSetting,
+        symmetries: &[Transform],
+    ) -> Option<CanonicalStateIndexer> {
+        let indexer = StateIndexer::try_new(setting)?;
+        let cells = setting.board_height() * setting.board_width();
+        let maps = symmetries
+            .iter()
+            .map(|&symmetry| {
+                (0..cells)
+                    .map(|cell| setting.cell(setting.take(cell).apply(symmetry, &setting)))
+                    .collect()
+            })
+            .collect();
+        let sides = (0..=setting.max_pieces())
+            .map(|len| [true, false].map(|is_next_first| setting.has_steps(len, is_next_first)))
+            .collect::<Vec<_>>();
+        let mut canonical = CanonicalStateIndexer {
+            indexer,
+            maps,
+            ranks: vec![],
+        };
+        for (len, &sides) in sides.iter().enumerate() {
+            if sides.contains(&true) {
+                let mut index = canonical.indexer.offsets[len];
+                canonical.visit(&mut vec![], 0, len, sides, &mut index);
+            }
+        }
+        Some(canonical)
+    }
+
+    /// Goes through the states of `len` cells that start with `cells`, in the order of their
+    /// ranks, and keeps the normalized ones with each side to move `sides` allows. `index` is
+    /// the rank of the first of them.
+    fn visit(
+        &mut self,
+        cells: &mut Vec<usize>,
+        used: u128,
+        len: usize,
+        sides: [bool; 2],
+        index: &mut u64,
+    ) {
+        let setting = self.indexer.setting;
+        let width = setting.board_width();
+        let board_cells = setting.board_height() * width;
+        if cells.len() < len {
+            for cell in (0..board_cells).filter(|&cell| used & (1 << cell) == 0) {
+                cells.push(cell);
+                if self.normalized_map(cells) == 0 {
+                    self.visit(cells, used | 1 << cell, len, sides, index);
+                } else {
+                    // A symmetry maps these cells onto smaller ones whatever follows them.
+                    *index +=
+                        self.indexer.permutations[board_cells - cells.len()][len - cells.len()];
+                }
+                cells.pop();
+            }
+            return;
+        }
+        // Under gravity every piece above the bottom row lies on another one.
+        let above_bottom = used & ((1 << (board_cells - width)) - 1);
+        let floating = setting.is_gravity() && (above_bottom << width) & !used != 0;
+        if !floating {
+            for (is_next_first, legal) in [true, false].into_iter().zip(sides) {
+                if legal {
+                    self.ranks.push(*index * 2 + !is_next_first as u64);
+                }
+            }
+        }
+        *index += 1;
+    }
+
+    pub fn setting(&self) -> &GameSetting {
+        self.indexer.setting()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.ranks.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranks.is_empty()
+    }
+
+    /// The index of the position the key is a symmetry of. Returns `None` if the key is not
+    /// numbered.
+    pub fn rank(&self, key: &AnalysisKey) -> Option<u64> {
+        self.indexer.rank(key)?;
+        let setting = self.indexer.setting;
+        let cells = key
             .state
             .takes
             .iter()
-            .skip(
-                if self.state.takes.len() >= (self.setting.piece_limit * 2).into() {
-                    1
-                } else {
-                    0
-                },
-            )
-            .chain(std::iter::once(&take))
-            .cloned()
-            .collect();
-        self.steps_taken += 1;
+            .map(|&take| setting.cell(take))
+            .collect::<Vec<_>>();
+        self.rank_cells(&cells, key.is_next_first)
     }
 
-    pub fn is_next_first(&self) -> bool {
-        (self.steps_taken % 2) == 0
+    /// Like `rank`, with the pieces given as distinct cells from the oldest.
+    fn rank_cells(&self, cells: &[usize], is_next_first: bool) -> Option<u64> {
+        let map = &self.maps[self.normalized_map(cells)];
+        let rank = self
+            .indexer
+            .rank_cells(cells.iter().map(|&cell| map[cell]), cells.len());
+        self.ranks
+            .binary_search(&(rank * 2 + !is_next_first as u64))
+            .ok()
+            .map(|index| index as u64)
     }
 
-    pub fn result(&self) -> GameResult {
-        if self.is_win(true) {
-            return GameResult::FirstWin;
+    /// The symmetry that maps the cells onto the normalized state, the first one if several
+    /// do. The cells are compared as `BoardState`s compare their takes, by column and then by
+    /// row.
+    fn normalized_map(&self, cells: &[usize]) -> usize {
+        let setting = &self.indexer.setting;
+        let order = |cell: usize| (cell % setting.board_width(), cell / setting.board_width());
+        (1..self.maps.len()).fold(0, |best, i| {
+            let mapped = cells.iter().map(|&cell| order(self.maps[i][cell]));
+            let best_mapped = cells.iter().map(|&cell| order(self.maps[best][cell]));
+            if mapped.lt(best_mapped) {
+                i
+            } else {
+                best
+            }
+        })
+    }
+
+    /// The normalized key numbered by the index. Returns `None` if the index is out of range.
+    pub fn unrank(&self, index: u64) -> Option<AnalysisKey> {
+        let rank = *self.ranks.get(usize::try_from(index).ok()?)?;
+        self.indexer.unrank(rank)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CellView {
+    None,
+    First(usize),
+    Second(usize),
+}
+impl CellView {
+    pub fn is_first(&self) -> bool {
+        matches!(self, CellView::First(_))
+    }
+    pub fn is_second(&self) -> bool {
+        matches!(self, CellView::Second(_))
+    }
+    pub fn is_none(&self) -> bool {
+        matches!(self, CellView::None)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameResult {
+    FirstWin,
+    SecondWin,
+    Continue,
+}
+impl GameResult {
+    pub fn is_win(&self) -> bool {
+        matches!(self, GameResult::FirstWin | GameResult::SecondWin)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TakeError {
+    OutOfBoard(Take),
+    Occupied(Take),
+}
+impl fmt::Display for TakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TakeError::OutOfBoard(take) => write!(f, "Out of board {}", take),
+            TakeError::Occupied(take) => write!(f, "Already taken {}", take),
         }
-        if self.is_win(false) {
-            return GameResult::SecondWin;
+    }
+}
+impl std::error::Error for TakeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    TooManyTakes {
+        limit: usize,
+        len: usize,
+    },
+    DuplicateCell(Take),
+    OutOfBoard(Take),
+    Floating(Take),
+    /// A player holds a number of pieces their takes cannot leave: each take adds a piece
+    /// until the player has as many as their limit.
+    PieceCount {
+        first: bool,
+        steps_taken: usize,
+        expected: usize,
+        len: usize,
+    },
+    BothWin,
+    InvalidHistory,
+}
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::TooManyTakes { limit, len } => write!(
+                f,
+                "Takes len should be up to the sum of the piece limits ({}), but {}",
+                limit, len
+            ),
+            StateError::DuplicateCell(take) => {
+                write!(f, "Should not take the same cell {}", take)
+            }
+            StateError::OutOfBoard(take) => write!(f, "Should not take out of board {}", take),
+            StateError::Floating(take) => {
+                write!(f, "Should rest on the bottom or another piece {}", take)
+            }
+            StateError::PieceCount {
+                first,
+                steps_taken,
+                expected,
+                len,
+            } => write!(
+                f,
+                "{} player should have {} pieces after {} steps, but {}",
+                if *first { "First" } else { "Second" },
+                expected,
+                steps_taken,
+                len
+            ),
+            StateError::BothWin => write!(f, "Both should not win at the same time"),
+            StateError::InvalidHistory => {
+                write!(f, "History should lead to the current state")
+            }
         }
-        GameResult::Continue
     }
+}
+impl std::error::Error for StateError {}
 
-    fn is_win(&self, first: bool) -> bool {
-        let f = |c: &CellView| {
-            matches!(
-                (first, c),
-                (true, CellView::First(_)) | (false, CellView::Second(_))
-            )
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LineDirection {
+    Row,
+    Column,
+    /// From top left to bottom right.
+    Diagonal,
+    /// From top right to bottom left.
+    AntiDiagonal,
+}
+impl LineDirection {
+    fn step(self) -> (isize, isize) {
+        match self {
+            LineDirection::Row => (1, 0),
+            LineDirection::Column => (0, 1),
+            LineDirection::Diagonal => (1, 1),
+            LineDirection::AntiDiagonal => (-1, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WinningLine {
+    pub direction: LineDirection,
+    pub takes: Vec<Take>,
+}
+
+/// What happened when a take was played.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TakeOutcome {
+    /// The cell the piece was placed on, which under gravity is the one it fell to.
+    pub take: Take,
+    /// The oldest piece that vanished to make room for the take, if any.
+    pub vanished: Option<Take>,
+    /// Whether the piece placed by the take completed a line of `goal` pieces.
+    pub completed_line: bool,
+    /// Whether pieces that fell onto the vanished piece under gravity completed a line of either
+    /// player.
+    pub completed_by_falling: bool,
+    pub result: GameResult,
+}
+
+/// A piece on the board and when it is going to vanish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VanishingPiece {
+    pub take: Take,
+    pub is_first: bool,
+    /// Value of `steps_taken` right after the take that makes the piece vanish, provided the
+    /// game lasts that long.
+    pub ply: usize,
+}
+
+/// Rules a `Game` is played by. Every method defaults to the rules of the original game, which
+/// `StandardRules` keeps as they are, so a variant only overrides what it changes. A player's
+/// pieces vanish oldest first once they have as many as their piece limit, as the
+/// `GameSetting` says. The methods describe a plain board: `Game` applies the misere, torus
+/// and gravity options of the setting on top of what they return.
+pub trait Ruleset: fmt::Debug + Clone {
+    /// Lines within the board that finish the game once a player fills one: by default `goal`
+    /// cells in a row, a column or a diagonal. On a torus `Game` adds every shift of them.
+    fn lines(&self, setting: &GameSetting) -> Vec<WinningLine> {
+        setting
+            .lines()
+            .into_iter()
+            .map(|(line, direction)| WinningLine {
+                direction,
+                takes: setting.takes_in(line),
+            })
+            .collect()
+    }
+
+    /// Result once the pieces of the first player, or of the second one if `first` is false,
+    /// fill a line: by default that player wins. In a misere setting `Game` turns the result
+    /// around.
+    fn line_result(&self, _setting: &GameSetting, first: bool) -> GameResult {
+        if first {
+            GameResult::FirstWin
+        } else {
+            GameResult::SecondWin
+        }
+    }
+
+    /// Whether the player to move may take a cell of the board that is free, see
+    /// `Game::is_free`: by default any such cell. Under gravity `Game` only asks about the cell
+    /// a piece dropped in each column lands on.
+    fn can_take(&self, game: &Game<Self>, take: Take) -> bool {
+        game.is_free(take)
+    }
+
+    /// Symmetries under which positions are equivalent, starting with the identity: by default
+    /// the rotations and mirrors of the board. `Game` keeps those that leave the bottom in
+    /// place under gravity, and follows each with every shift on a torus, see `Transform::of`.
+    fn symmetries(&self, setting: &GameSetting) -> Vec<Symmetry> {
+        Symmetry::all(setting).to_vec()
+    }
+
+    /// Which piece of the first player, or of the second one if `first` is false, vanishes
+    /// when they take with as many pieces as their limit, counted from their oldest piece:
+    /// the oldest one. Should be less than the piece limit of the player.
+    fn vanishing_index(&self, _setting: &GameSetting, _first: bool) -> usize {
+        0
+    }
+}
+
+/// The rules of the original game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StandardRules;
+impl Ruleset for StandardRules {}
+
+const MAX_CELLS: usize = 121;
+
+#[derive(Debug, Clone)]
+struct Bitboard {
+    pieces: [u128; 2],
+    /// Each player's cells from their oldest piece, as ring buffers.
+    rings: [[u8; MAX_CELLS]; 2],
+    oldest: [u8; 2],
+    lens: [u8; 2],
+}
+impl Bitboard {
+    fn new() -> Bitboard {
+        Bitboard {
+            pieces: [0; 2],
+            rings: [[0; MAX_CELLS]; 2],
+            oldest: [0; 2],
+            lens: [0; 2],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.lens.iter().map(|&len| len as usize).sum()
+    }
+
+    fn player_len(&self, first: bool) -> usize {
+        self.lens[player_index(first)] as usize
+    }
+
+    fn occupied(&self) -> u128 {
+        self.pieces[0] | self.pieces[1]
+    }
+
+    fn owner_mask(&self, first: bool) -> u128 {
+        self.pieces[player_index(first)]
+    }
+
+    /// The `i`-th oldest piece of the player.
+    fn cell(&self, first: bool, i: usize, capacity: usize) -> usize {
+        let p = player_index(first);
+        self.rings[p][(self.oldest[p] as usize + i) % capacity] as usize
+    }
+
+    fn push(&mut self, cell: usize, first: bool, capacity: usize) {
+        let p = player_index(first);
+        self.rings[p][(self.oldest[p] as usize + self.lens[p] as usize) % capacity] = cell as u8;
+        self.lens[p] += 1;
+        self.pieces[p] |= 1 << cell;
+    }
+
+    /// Removes the `i`-th oldest piece of the player, returning its cell.
+    fn remove(&mut self, first: bool, i: usize, capacity: usize) -> usize {
+        let p = player_index(first);
+        let cell = self.cell(first, i, capacity);
+        // The older pieces move up by one slot onto the removed one.
+        for j in (0..i).rev() {
+            let from = (self.oldest[p] as usize + j) % capacity;
+            self.rings[p][(from + 1) % capacity] = self.rings[p][from];
+        }
+        self.oldest[p] = ((self.oldest[p] as usize + 1) % capacity) as u8;
+        self.lens[p] -= 1;
+        self.pieces[p] &= !(1 << cell);
+        cell
+    }
+
+    fn pop_newest(&mut self, first: bool, capacity: usize) -> usize {
+        let p = player_index(first);
+        let cell = self.cell(first, self.lens[p] as usize - 1, capacity);
+        self.lens[p] -= 1;
+        self.pieces[p] &= !(1 << cell);
+        cell
+    }
+
+    /// Moves the piece on `from` to `to`, which should be empty, returning its owner and its
+    /// index from the owner's oldest piece.
+    fn relocate(&mut self, from: usize, to: usize, capacities: [usize; 2]) -> (bool, usize) {
+        let first = self.pieces[0] & (1 << from) != 0;
+        let p = player_index(first);
+        let i = (0..self.lens[p] as usize)
+            .find(|&i| self.cell(first, i, capacities[p]) == from)
+            .unwrap();
+        self.rings[p][(self.oldest[p] as usize + i) % capacities[p]] = to as u8;
+        self.pieces[p] = self.pieces[p] & !(1 << from) | 1 << to;
+        (first, i)
+    }
+
+    /// Undoes `remove`, putting the piece back as the `i`-th oldest piece of the player.
+    fn insert(&mut self, cell: usize, first: bool, i: usize, capacity: usize) {
+        let p = player_index(first);
+        self.oldest[p] = ((self.oldest[p] as usize + capacity - 1) % capacity) as u8;
+        for j in 0..i {
+            let to = (self.oldest[p] as usize + j) % capacity;
+            self.rings[p][to] = self.rings[p][(to + 1) % capacity];
+        }
+        self.rings[p][(self.oldest[p] as usize + i) % capacity] = cell as u8;
+        self.lens[p] += 1;
+        self.pieces[p] |= 1 << cell;
+    }
+}
+
+fn player_index(first: bool) -> usize {
+    if first {
+        0
+    } else {
+        1
+    }
+}
+
+const fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+const fn zobrist_table() -> [[u128; 2]; MAX_CELLS] {
+    let mut table = [[0; 2]; MAX_CELLS];
+    let mut cell = 0;
+    while cell < MAX_CELLS {
+        let mut player = 0;
+        while player < 2 {
+            let seed = 4 * (cell * 2 + player) as u64 + 1;
+            table[cell][player] = (splitmix64(seed) as u128) << 64 | splitmix64(seed + 2) as u128;
+            player += 1;
+        }
+        cell += 1;
+    }
+    table
+}
+
+/// Random keys per cell and player. Each player's pieces are hashed on their own, with a
+/// piece's key rotated left by its age slot counted from the player's oldest piece, so that
+/// vanishing that piece only needs one rotation of the player's hash. A player has at most
+/// `MAX_CELLS` pieces, so the slots are distinct rotations of the 128 bits. Folding the
+/// halves together by xor alone would turn slots 64 apart into the same rotation of 64 bits,
+/// so `fold_zobrist` mixes the high half first.
+const ZOBRIST: [[u128; 2]; MAX_CELLS] = zobrist_table();
+const ZOBRIST_SECOND_TO_MOVE: u128 = splitmix64(2) as u128;
+
+fn fold_zobrist(hash: u128) -> u64 {
+    splitmix64((hash >> 64) as u64) ^ hash as u64
+}
+
+#[derive(Debug)]
+struct Geometry {
+    symmetries: Vec<Transform>,
+    lines: Vec<(u128, LineDirection)>,
+    lines_through: Vec<Vec<u128>>,
+    /// Zobrist keys indexed by symmetry and then by cell, i.e. the keys of the cells each cell
+    /// is mapped to.
+    zobrist: Vec<Vec<[u128; 2]>>,
+}
+impl Geometry {
+    fn new<R: Ruleset>(setting: &GameSetting, rules: &R) -> Geometry {
+        let cells = setting.board_height() * setting.board_width();
+        let symmetries = rules.symmetries(setting);
+        assert!(
+            symmetries.first() == Some(&Symmetry::Identity),
+            "Symmetries should start with the identity"
+        );
+        let symmetries = Transform::of(setting, &symmetries);
+        // On a torus the lines go on across the edges, so each shift of a line is one too.
+        let shifts = if setting.is_torus() {
+            (0..setting.board_height() as u8)
+                .flat_map(|dy| {
+                    (0..setting.board_width() as u8).map(move |dx| Transform {
+                        symmetry: Symmetry::Identity,
+                        dx,
+                        dy,
+                    })
+                })
+                .collect()
+        } else {
+            vec![Symmetry::Identity.into()]
         };
-        let cells = self
-            .to_cells()
+        let mut seen = HashSet::new();
+        let lines = rules
+            .lines(setting)
             .into_iter()
-            .map(|row| row.into_iter().map(|cell| f(&cell)).collect::<Vec<_>>())
+            .flat_map(|line| {
+                shifts.iter().map(move |&shift| {
+                    let mask = line.takes.iter().fold(0, |mask, &take| {
+                        mask | 1 << setting.cell(take.apply(shift, setting))
+                    });
+                    (mask, line.direction)
+                })
+            })
+            .filter(|&(mask, _)| seen.insert(mask))
             .collect::<Vec<_>>();
-        for y_base in 0..=self.setting.board_height() - self.setting.goal() {
-            for x_base in 0..=self.setting.board_width() - self.setting.goal() {
-                if (0..self.setting.goal()).all(|i| cells[y_base + i][x_base + i]) {
-                    return true;
+        let lines_through = (0..cells)
+            .map(|cell| {
+                lines
+                    .iter()
+                    .map(|&(line, _)| line)
+                    .filter(|line| line & (1 << cell) != 0)
+                    .collect()
+            })
+            .collect();
+        let all_cells = BoardState {
+            takes: (0..cells).map(|cell| setting.take(cell)).collect(),
+        };
+        let zobrist = symmetries
+            .iter()
+            .map(|&symmetry| {
+                all_cells
+                    .apply(symmetry, setting)
+                    .takes
+                    .iter()
+                    .map(|&take| ZOBRIST[setting.cell(take)])
+                    .collect()
+            })
+            .collect();
+        Geometry {
+            symmetries,
+            lines,
+            lines_through,
+            zobrist,
+        }
+    }
+}
+
+/// A game played by the rules `R`. A `Game` with the standard rules is serialized through a
+/// versioned save, which is verified when loaded.
+#[derive(Debug, Clone)]
+pub struct Game<R = StandardRules> {
+    setting: GameSetting,
+    rules: R,
+    board: Bitboard,
+    geometry: Arc<Geometry>,
+    /// Hashes indexed by symmetry and then by player, kept up to date for the first symmetries
+    /// only.
+    zobrist: [[u128; 2]; 8],
+    result: GameResult,
+    steps_taken: usize,
+    /// The pieces as a `BoardState`, built from the board the first time it is asked for after
+    /// a change.
+    state: OnceLock<BoardState>,
+    /// Takes played since the game started or its state was last replaced, with the take each
+    /// of them made vanish.
+    history: Vec<Take>,
+    vanished: Vec<Option<Take>>,
+    /// Undone takes, the most recently undone last.
+    undone: Vec<Take>,
+}
+impl Game {
+    pub fn new(setting: GameSetting) -> Game {
+        Game::with_rules(setting, StandardRules)
+    }
+
+    /// Parses the output of `position`.
+    pub fn from_position(s: &str) -> Result<Game, ParsePositionError> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let &[setting, rows, side, steps_taken] = fields.as_slice() else {
+            return Err(ParsePositionError::InvalidFormat(s.to_string()));
+        };
+        let setting = GameSetting::from_str(setting).map_err(ParsePositionError::Setting)?;
+        let cells = rows
+            .split('/')
+            .map(|row| {
+                let invalid = || ParsePositionError::InvalidRow(row.to_string());
+                let mut cells = vec![];
+                let mut chars = row.chars().peekable();
+                while let Some(c) = chars.next() {
+                    if c == '.' {
+                        cells.push(CellView::None);
+                        continue;
+                    }
+                    let mut digits = String::new();
+                    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(d);
+                    }
+                    let rest = digits.parse().map_err(|_| invalid())?;
+                    cells.push(match c {
+                        'o' => CellView::First(rest),
+                        'x' => CellView::Second(rest),
+                        _ => return Err(invalid()),
+                    });
                 }
-                if (0..self.setting.goal())
-                    .all(|i| cells[y_base + i][x_base + self.setting.goal() - i - 1])
-                {
-                    return true;
+                Ok(cells)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let steps_taken = steps_taken
+            .parse::<usize>()
+            .map_err(|_| ParsePositionError::InvalidSteps(steps_taken.to_string()))?;
+        let is_next_first = match side {
+            "o" => true,
+            "x" => false,
+            _ => return Err(ParsePositionError::InvalidSide(side.to_string())),
+        };
+        if is_next_first != (steps_taken % 2 == 0) {
+            return Err(ParsePositionError::SideMismatch);
+        }
+        let state = BoardState::from_cells(&setting, &cells, steps_taken)
+            .ok_or(ParsePositionError::InvalidPieces)?;
+        let mut game = Game::new(setting);
+        game.try_replace_state(state, steps_taken)
+            .map_err(ParsePositionError::State)?;
+        Ok(game)
+    }
+}
+impl<R: Ruleset> Game<R> {
+    pub fn with_rules(setting: GameSetting, rules: R) -> Game<R> {
+        let geometry = Arc::new(Geometry::new(&setting, &rules));
+        Game {
+            setting,
+            rules,
+            board: Bitboard::new(),
+            geometry,
+            zobrist: [[0; 2]; 8],
+            result: GameResult::Continue,
+            steps_taken: 0,
+            state: OnceLock::new(),
+            history: vec![],
+            vanished: vec![],
+            undone: vec![],
+        }
+    }
+
+    pub fn rules(&self) -> &R {
+        &self.rules
+    }
+
+    pub fn add_take(&mut self, take: Take) -> TakeOutcome {
+        let outcome = self.play(take);
+        self.history.push(outcome.take);
+        self.vanished.push(outcome.vanished);
+        self.undone.clear();
+        outcome
+    }
+
+    /// Plays a take without recording it in the history.
+    fn play(&mut self, take: Take) -> TakeOutcome {
+        assert!(!self.is_finished(), "Game already finished");
+        self.state = OnceLock::new();
+        let take = self.placed(take);
+        let first = self.is_next_first();
+        let vanished = if self.board.player_len(first) >= self.setting.player_piece_limit(first) {
+            Some(self.vanish(first))
+        } else {
+            None
+        };
+        let fallen = vanished.map_or(0, |vanished| self.fall_onto(vanished));
+        let cell = self.setting.cell(take);
+        self.push(cell, first);
+        self.steps_taken += 1;
+        // Only the mover's lines through the new piece can have been completed; the vanished
+        // piece can only break lines. Pieces that fell may have completed any line.
+        let pieces = self.board.owner_mask(first);
+        let completed_line = self.geometry.lines_through[cell]
+            .iter()
+            .any(|&line| pieces & line == line);
+        let completed_by_falling = fallen != 0
+            && self.geometry.lines.iter().any(|&(line, _)| {
+                line & fallen != 0
+                    && [true, false]
+                        .iter()
+                        .any(|&first| self.board.owner_mask(first) & line == line)
+            });
+        self.result = if completed_by_falling {
+            self.full_scan_result()
+        } else if completed_line {
+            self.line_result(first)
+        } else {
+            GameResult::Continue
+        };
+        debug_assert_eq!(self.result, self.full_scan_result());
+        TakeOutcome {
+            take,
+            vanished,
+            completed_line,
+            completed_by_falling,
+            result: self.result.clone(),
+        }
+    }
+
+    /// Takes back the last take, bringing back the piece it made vanish. Returns the take, or
+    /// `None` if there is nothing to undo.
+    pub fn undo(&mut self) -> Option<Take> {
+        let take = self.history.pop()?;
+        let vanished = self.vanished.pop().unwrap();
+        self.state = OnceLock::new();
+        let first = !self.is_next_first();
+        let p = player_index(first);
+        let cell = self
+            .board
+            .pop_newest(first, self.setting.ring_capacity(first));
+        let slot = self.board.player_len(first) as u32;
+        for (hashes, keys) in self.zobrist.iter_mut().zip(self.geometry.zobrist.iter()) {
+            hashes[p] ^= keys[cell][p].rotate_left(slot);
+        }
+        self.steps_taken -= 1;
+        if let Some(vanished) = vanished {
+            // The vanished piece belongs to the player who made the take.
+            self.rise_from(vanished);
+            let cell = self.setting.cell(vanished);
+            let i = self.vanishing_index(first);
+            self.board
+                .insert(cell, first, i, self.setting.ring_capacity(first));
+            if i == 0 {
+                for (hashes, keys) in self.zobrist.iter_mut().zip(self.geometry.zobrist.iter()) {
+                    hashes[p] = hashes[p].rotate_left(1) ^ keys[cell][p];
                 }
+            } else {
+                self.rehash(first);
             }
         }
-        for y_base in 0..=self.setting.board_height() - self.setting.goal() {
-            for x in 0..self.setting.board_width() {
-                if (0..self.setting.goal()).all(|i| cells[y_base + i][x]) {
-                    return true;
-                }
+        self.result = GameResult::Continue;
+        self.undone.push(take);
+        Some(take)
+    }
+
+    /// Plays the last undone take again. Returns the take, or `None` if there is nothing to
+    /// redo.
+    pub fn redo(&mut self) -> Option<Take> {
+        let take = self.undone.pop()?;
+        let outcome = self.play(take);
+        self.history.push(take);
+        self.vanished.push(outcome.vanished);
+        Some(take)
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.history.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.undone.is_empty()
+    }
+
+    /// Every take played since the game started or its state was last replaced, including
+    /// the ones that have vanished since.
+    pub fn history(&self) -> &[Take] {
+        &self.history
+    }
+
+    /// The history as a move list that can be pasted back into `MoveList::from_str`.
+    pub fn move_list(&self) -> MoveList {
+        MoveList(self.history.clone())
+    }
+
+    fn push(&mut self, cell: usize, first: bool) {
+        let p = player_index(first);
+        let slot = self.board.player_len(first) as u32;
+        for (hashes, keys) in self.zobrist.iter_mut().zip(self.geometry.zobrist.iter()) {
+            hashes[p] ^= keys[cell][p].rotate_left(slot);
+        }
+        self.board
+            .push(cell, first, self.setting.ring_capacity(first));
+    }
+
+    /// Under gravity, the take as the cell the piece lands on. Otherwise the take itself.
+    fn placed(&self, take: Take) -> Take {
+        if !self.setting.is_gravity() || take.x() >= self.setting.board_width() {
+            return take;
+        }
+        self.landing(take.x()).unwrap_or(take)
+    }
+
+    /// Under gravity, lets the pieces above the vanished take fall down by one cell. Returns
+    /// the cells the pieces fell to.
+    fn fall_onto(&mut self, vanished: Take) -> u128 {
+        if !self.setting.is_gravity() {
+            return 0;
+        }
+        let mut fallen = 0;
+        for y in (0..vanished.y()).rev() {
+            let from = self.setting.cell(Take {
+                x: vanished.x,
+                y: y as u8,
+            });
+            if self.board.occupied() & (1 << from) == 0 {
+                break;
             }
+            self.relocate(from, from + self.setting.board_width());
+            fallen |= 1 << (from + self.setting.board_width());
         }
-        for x_base in 0..=self.setting.board_width() - self.setting.goal() {
-            for row in cells.iter() {
-                if (0..self.setting.goal()).all(|i| row[x_base + i]) {
-                    return true;
-                }
+        fallen
+    }
+
+    /// Undoes `fall_onto`, lifting the pieces on and above the vanished take up by one cell.
+    fn rise_from(&mut self, vanished: Take) {
+        if !self.setting.is_gravity() {
+            return;
+        }
+        for y in 0..=vanished.y() {
+            let from = self.setting.cell(Take {
+                x: vanished.x,
+                y: y as u8,
+            });
+            if self.board.occupied() & (1 << from) != 0 {
+                self.relocate(from, from - self.setting.board_width());
+            }
+        }
+    }
+
+    fn relocate(&mut self, from: usize, to: usize) {
+        let capacities = [
+            self.setting.ring_capacity(true),
+            self.setting.ring_capacity(false),
+        ];
+        let (first, i) = self.board.relocate(from, to, capacities);
+        let p = player_index(first);
+        for (hashes, keys) in self.zobrist.iter_mut().zip(self.geometry.zobrist.iter()) {
+            hashes[p] ^= (keys[from][p] ^ keys[to][p]).rotate_left(i as u32);
+        }
+    }
+
+    /// Index from the oldest piece of the player of the piece their take makes vanish.
+    fn vanishing_index(&self, first: bool) -> usize {
+        let i = self.rules.vanishing_index(&self.setting, first);
+        assert!(
+            i < self.setting.player_piece_limit(first),
+            "Vanishing index should be less than the piece limit"
+        );
+        i
+    }
+
+    /// Removes the piece of the player that vanishes with their take.
+    fn vanish(&mut self, first: bool) -> Take {
+        let p = player_index(first);
+        let i = self.vanishing_index(first);
+        let cell = self
+            .board
+            .remove(first, i, self.setting.ring_capacity(first));
+        if i == 0 {
+            // Every other piece moves down one slot, which is one rotation of the hash.
+            for (hashes, keys) in self.zobrist.iter_mut().zip(self.geometry.zobrist.iter()) {
+                hashes[p] = (hashes[p] ^ keys[cell][p]).rotate_right(1);
+            }
+        } else {
+            self.rehash(first);
+        }
+        self.setting.take(cell)
+    }
+
+    /// Recomputes the hashes of the player's pieces kept up to date.
+    fn rehash(&mut self, first: bool) {
+        let p = player_index(first);
+        let hashes = self
+            .geometry
+            .zobrist
+            .iter()
+            .take(self.zobrist.len())
+            .map(|keys| self.player_hash(keys, first))
+            .collect::<Vec<_>>();
+        for (hashes, hash) in self.zobrist.iter_mut().zip(hashes) {
+            hashes[p] = hash;
+        }
+    }
+
+    /// Hash of the player's pieces with the keys of a symmetry.
+    fn player_hash(&self, keys: &[[u128; 2]], first: bool) -> u128 {
+        let p = player_index(first);
+        (0..self.board.player_len(first)).fold(0, |hash, i| {
+            let cell = self.board.cell(first, i, self.setting.ring_capacity(first));
+            hash ^ keys[cell][p].rotate_left(i as u32)
+        })
+    }
+
+    /// Hash of the pieces hashed with a symmetry and the side to move, before it is folded to
+    /// 64 bits.
+    fn wide_zobrist(&self, hashes: [u128; 2]) -> u128 {
+        let side = if self.is_next_first() {
+            0
+        } else {
+            ZOBRIST_SECOND_TO_MOVE
+        };
+        hashes[0] ^ hashes[1] ^ side
+    }
+
+    /// 64-bit Zobrist key of the position, including the side to move.
+    pub fn zobrist(&self) -> u64 {
+        fold_zobrist(self.wide_zobrist(self.zobrist[0]))
+    }
+
+    /// Zobrist key shared by all symmetries of the position. Distinct positions may collide
+    /// with a negligible probability.
+    pub fn canonical_zobrist(&self) -> u64 {
+        fold_zobrist(self.canonical_wide_zobrist())
+    }
+
+    /// The smallest 128-bit hash among the symmetries of the position.
+    fn canonical_wide_zobrist(&self) -> u128 {
+        if self.geometry.zobrist.len() <= self.zobrist.len() {
+            return self.zobrist[..self.geometry.zobrist.len()]
+                .iter()
+                .map(|&hash| self.wide_zobrist(hash))
+                .min()
+                .unwrap();
+        }
+        // There are more symmetries than hashes kept up to date, as shifts on a torus add
+        // them, so every hash is computed from the pieces.
+        self.geometry
+            .zobrist
+            .iter()
+            .map(|keys| {
+                self.wide_zobrist([self.player_hash(keys, true), self.player_hash(keys, false)])
+            })
+            .min()
+            .unwrap()
+    }
+
+    pub fn setting(&self) -> &GameSetting {
+        &self.setting
+    }
+
+    pub fn is_next_first(&self) -> bool {
+        (self.steps_taken % 2) == 0
+    }
+
+    pub fn result(&self) -> GameResult {
+        self.result.clone()
+    }
+
+    /// Under gravity, falling pieces can complete lines of both players at once, and then the
+    /// line of the player who just moved decides.
+    fn full_scan_result(&self) -> GameResult {
+        let mover = !self.is_next_first();
+        for first in [mover, !mover] {
+            if self.is_win(first) {
+                return self.line_result(first);
+            }
+        }
+        GameResult::Continue
+    }
+
+    /// Result by the rules once the pieces of the player fill a line, turned around if the
+    /// setting is misere.
+    fn line_result(&self, first: bool) -> GameResult {
+        let result = self.rules.line_result(&self.setting, first);
+        if !self.setting.is_misere() {
+            return result;
+        }
+        match result {
+            GameResult::FirstWin => GameResult::SecondWin,
+            GameResult::SecondWin => GameResult::FirstWin,
+            GameResult::Continue => GameResult::Continue,
+        }
+    }
+
+    fn is_win(&self, first: bool) -> bool {
+        let pieces = self.board.owner_mask(first);
+        self.geometry
+            .lines
+            .iter()
+            .any(|&(line, _)| pieces & line == line)
+    }
+
+    /// Every completed line on the board, with its takes in row-major order.
+    pub fn winning_lines(&self) -> Vec<WinningLine> {
+        let pieces = [self.board.owner_mask(true), self.board.owner_mask(false)];
+        self.geometry
+            .lines
+            .iter()
+            .filter(|&&(line, _)| pieces.iter().any(|&pieces| pieces & line == line))
+            .map(|&(line, direction)| WinningLine {
+                direction,
+                takes: self.setting.takes_in(line),
+            })
+            .collect()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result().is_win() || self.valid_take_count() == 0
+    }
+
+    pub fn valid_take_count(&self) -> usize {
+        self.takable_mask().count_ones() as usize
+    }
+
+    /// Every take that passes `validate_take`, in row-major order.
+    pub fn valid_takes(&self) -> impl Iterator<Item = Take> + '_ {
+        let mut takable = self.takable_mask();
+        std::iter::from_fn(move || {
+            if takable == 0 {
+                return None;
             }
+            let cell = takable.trailing_zeros() as usize;
+            takable &= takable - 1;
+            Some(self.setting.take(cell))
+        })
+    }
+
+    /// Every valid take paired with the game after it, in row-major order. The games start a
+    /// new history from their position, so that exploring them does not copy the history over
+    /// and over. With `dedup`, only the first take leading to each position up to symmetry is
+    /// kept. Empty when the game is finished.
+    pub fn successors(&self, dedup: bool) -> Vec<(Take, Game<R>)> {
+        if self.result().is_win() {
+            return vec![];
         }
-        false
+        let mut seen = HashSet::new();
+        self.valid_takes()
+            .filter_map(|take| {
+                let mut game = self.without_history();
+                game.play(take);
+                (!dedup || seen.insert(game.analysis_key())).then_some((take, game))
+            })
+            .collect()
     }
 
-    pub fn new(setting: GameSetting) -> Game {
+    /// The game at the same position with an empty history.
+    fn without_history(&self) -> Game<R> {
         Game {
-            setting,
-            state: BoardState::default(),
-            steps_taken: 0,
+            setting: self.setting,
+            rules: self.rules.clone(),
+            board: self.board.clone(),
+            geometry: self.geometry.clone(),
+            zobrist: self.zobrist,
+            result: self.result.clone(),
+            steps_taken: self.steps_taken,
+            state: self.state.clone(),
+            history: vec![],
+            vanished: vec![],
+            undone: vec![],
         }
     }
 
-    pub fn is_finished(&self) -> bool {
-        self.result().is_win() || self.valid_take_count() == 0
+    fn takable_mask(&self) -> u128 {
+        if self.setting.is_gravity() {
+            return (0..self.setting.board_width())
+                .filter_map(|x| self.landing(x))
+                .filter(|&take| self.rules.can_take(self, take))
+                .fold(0, |takable, take| takable | 1 << self.setting.cell(take));
+        }
+        (0..self.setting.board_height() * self.setting.board_width())
+            .filter(|&cell| self.rules.can_take(self, self.setting.take(cell)))
+            .fold(0, |takable, cell| takable | 1 << cell)
     }
 
-    pub fn valid_take_count(&self) -> usize {
-        let mut count = 0;
-        for y in 0..self.setting.board_height() {
-            for x in 0..self.setting.board_width() {
-                if self
-                    .validate_take(Take {
-                        x: x as u8,
-                        y: y as u8,
-                    })
-                    .is_ok()
-                {
-                    count += 1;
-                }
-            }
+    /// Whether no piece is on the cell, which should be inside the board.
+    pub fn is_vacant(&self, take: Take) -> bool {
+        self.board.occupied() & (1 << self.setting.cell(take)) == 0
+    }
+
+    /// Whether the cell, which should be inside the board, is empty once the piece the next
+    /// take makes vanish is gone and, under gravity, the pieces above it have fallen.
+    pub fn is_free(&self, take: Take) -> bool {
+        if self.setting.is_gravity() {
+            return self
+                .landing(take.x())
+                .is_some_and(|landing| take.y() <= landing.y());
         }
-        count
+        self.is_vacant(take)
+            || self
+                .next_vanishing()
+                .is_some_and(|piece| piece.take == take)
     }
 
-    pub fn validate_take(&self, take: Take) -> Result<(), String> {
+    /// Under gravity, any cell of a column stands for the cell a piece dropped in it lands on.
+    pub fn validate_take(&self, take: Take) -> Result<(), TakeError> {
         if take.x() >= self.setting.board_width() || take.y() >= self.setting.board_height() {
-            return Err("Out of board".to_string());
+            return Err(TakeError::OutOfBoard(take));
         }
-        let untakable = self.state.takes.iter().skip(
-            if self.state.takes.len() >= (self.setting.piece_limit * 2).into() {
-                1
-            } else {
-                0
-            },
-        );
-        for t in untakable {
-            if t.x == take.x && t.y == take.y {
-                return Err("Already taken".to_string());
-            }
+        let take = self.placed(take);
+        if self.takable_mask() & (1 << self.setting.cell(take)) == 0 {
+            return Err(TakeError::Occupied(take));
         }
         Ok(())
     }
 
+    /// Under gravity, the cell a piece dropped in the column lands on once the next vanishing
+    /// piece is gone and the pieces above it have fallen, or `None` if the column is full or
+    /// out of the board.
+    pub fn landing(&self, x: usize) -> Option<Take> {
+        if x >= self.setting.board_width() {
+            return None;
+        }
+        let height = self.setting.board_height();
+        let pieces = (0..height)
+            .filter(|&y| {
+                self.board.occupied()
+                    & (1 << self.setting.cell(Take {
+                        x: x as u8,
+                        y: y as u8,
+                    }))
+                    != 0
+            })
+            .count();
+        let vanishing = self
+            .next_vanishing()
+            .is_some_and(|piece| piece.take.x() == x);
+        let pieces = pieces - vanishing as usize;
+        (pieces < height).then(|| Take {
+            x: x as u8,
+            y: (height - 1 - pieces) as u8,
+        })
+    }
+
+    /// The piece the next take removes, if the board is full.
+    pub fn next_vanishing(&self) -> Option<VanishingPiece> {
+        let first = self.is_next_first();
+        if self.board.player_len(first) < self.setting.player_piece_limit(first) {
+            return None;
+        }
+        let cell = self.board.cell(
+            first,
+            self.vanishing_index(first),
+            self.setting.ring_capacity(first),
+        );
+        Some(VanishingPiece {
+            take: self.setting.take(cell),
+            is_first: first,
+            ply: self.steps_taken + 1,
+        })
+    }
+
+    /// Every piece on the board that is going to vanish, the one vanishing first first.
+    pub fn vanish_schedule(&self) -> Vec<VanishingPiece> {
+        let mut schedule = self
+            .pieces()
+            .into_iter()
+            .filter_map(|(take, first, rest)| {
+                // Once the player has as many pieces as their limit, each of their takes makes
+                // the piece at the vanishing index vanish, so the pieces older than it stay.
+                let unfilled =
+                    self.setting.player_piece_limit(first) - self.board.player_len(first);
+                let takes = rest
+                    .checked_sub(self.vanishing_index(first))
+                    .filter(|&rest| rest >= unfilled)?
+                    + 1;
+                Some(VanishingPiece {
+                    take,
+                    is_first: first,
+                    // The piece vanishes with the player's `takes`-th take from now.
+                    ply: self.steps_taken + 2 * takes
+                        - if first == self.is_next_first() { 1 } else { 0 },
+                })
+            })
+            .collect::<Vec<_>>();
+        schedule.sort_by_key(|piece| piece.ply);
+        schedule
+    }
+
+    /// Every piece from the oldest with its owner and its number: how many more takes its
+    /// owner makes before the take that makes it vanish, were the oldest piece to vanish first.
+    fn pieces(&self) -> Vec<(Take, bool, usize)> {
+        let mut next = [0, 0];
+        self.setting
+            .owners(self.board.len(), self.is_next_first())
+            .into_iter()
+            .map(|first| {
+                let i = &mut next[player_index(first)];
+                let cell = self
+                    .board
+                    .cell(first, *i, self.setting.ring_capacity(first));
+                let rest =
+                    self.setting.player_piece_limit(first) - self.board.player_len(first) + *i;
+                *i += 1;
+                (self.setting.take(cell), first, rest)
+            })
+            .collect()
+    }
+
     pub fn to_cells(&self) -> Vec<Vec<CellView>> {
         let mut board =
             vec![vec![CellView::None; self.setting.board_width()]; self.setting.board_height()];
-        for (i, take) in self.state.takes.iter().enumerate() {
-            let rest = ((self.setting.piece_limit() * 2 - self.state.takes.len()) + i) / 2;
-            let cell = match ((self.state.takes.len() - i + 1) % 2) ^ (self.steps_taken % 2) {
-                0 => CellView::Second(rest),
-                _ => CellView::First(rest),
+        for (take, first, rest) in self.pieces() {
+            board[take.y()][take.x()] = if first {
+                CellView::First(rest)
+            } else {
+                CellView::Second(rest)
             };
-            board[take.y as usize][take.x as usize] = cell;
         }
         board
     }
 
     pub fn state(&self) -> &BoardState {
-        &self.state
+        self.state.get_or_init(|| BoardState {
+            takes: self.pieces().into_iter().map(|(take, _, _)| take).collect(),
+        })
     }
     pub fn steps_taken(&self) -> usize {
         self.steps_taken
     }
     pub fn replace_state(&mut self, state: BoardState, steps_taken: usize) {
-        self.state = state;
+        self.try_replace_state(state, steps_taken)
+            .expect("Invalid state");
+    }
+
+    /// Like `replace_state`, but leaves the game untouched and returns the error if the state
+    /// is invalid.
+    pub fn try_replace_state(
+        &mut self,
+        state: BoardState,
+        steps_taken: usize,
+    ) -> Result<(), StateError> {
+        state.verify(&self.setting)?;
+        let mut new_game = self.clone();
+        new_game.set_state(&state, steps_taken);
+        new_game.verify_full()?;
+        *self = new_game;
+        Ok(())
+    }
+
+    /// One-line description of the position: the setting, the rows from the top separated by
+    /// `/`, the player to move and the number of steps taken, e.g.
+    /// `3x3:3:3 o0x1o2/.o1./x2.. x 5`. Each piece is written with its number from `to_cells`
+    /// and `.` is an empty cell. The history is not included.
+    pub fn position(&self) -> String {
+        let rows = self
+            .to_cells()
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| match cell {
+                        CellView::None => ".".to_string(),
+                        CellView::First(rest) => format!("o{}", rest),
+                        CellView::Second(rest) => format!("x{}", rest),
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        format!(
+            "{} {} {} {}",
+            self.setting,
+            rows,
+            if self.is_next_first() { 'o' } else { 'x' },
+            self.steps_taken
+        )
+    }
+
+    fn set_state(&mut self, state: &BoardState, steps_taken: usize) {
+        self.board = Bitboard::new();
+        self.zobrist = [[0; 2]; 8];
         self.steps_taken = steps_taken;
-        self.verify_full().expect("Invalid state");
+        self.state = OnceLock::new();
+        let owners = self.setting.owners(state.takes.len(), self.is_next_first());
+        for (take, first) in state.takes.iter().zip(owners) {
+            self.push(self.setting.cell(*take), first);
+        }
+        self.result = self.full_scan_result();
+        self.history.clear();
+        self.vanished.clear();
+        self.undone.clear();
     }
 
-    pub fn verify_full(&self) -> Result<(), String> {
-        // takes len should be up to piece_count * 2
-        if self.state.takes.len() > (self.setting.piece_limit * 2).into() {
-            return Err(format!(
-                "Takes len should be up to piece_count * 2 ({}), but {}",
-                self.setting.piece_limit * 2,
-                self.state.takes.len()
-            ));
+    /// Checks that every take in the history can be undone and every undone take redone.
+    fn verify_history(&self) -> Result<(), StateError> {
+        if self.history.len() != self.vanished.len() || self.history.len() > self.steps_taken {
+            return Err(StateError::InvalidHistory);
         }
-        // should not take the same cell
-        {
-            let mut set = std::collections::HashSet::new();
-            for take in self.state.takes.iter() {
-                if !set.insert(take) {
-                    return Err(format!("Should not take the same cell {:?}", take));
+        let mut game = self.clone();
+        while let (Some(&take), Some(&vanished)) = (game.history.last(), game.vanished.last()) {
+            if !game.is_last_take(take) {
+                return Err(StateError::InvalidHistory);
+            }
+            if let Some(vanished) = vanished {
+                let occupied = game.board.occupied() & !(1 << game.setting.cell(take));
+                let first = !game.is_next_first();
+                if game.board.player_len(first) != game.setting.player_piece_limit(first)
+                    || vanished.x() >= game.setting.board_width()
+                    || vanished.y() >= game.setting.board_height()
+                {
+                    return Err(StateError::InvalidHistory);
+                }
+                let height = game.setting.board_height();
+                let vacant = if game.setting.is_gravity() {
+                    // The pieces that fell onto the vanished piece should be able to rise.
+                    let pieces = (0..height)
+                        .filter(|&y| {
+                            let cell = game.setting.cell(Take {
+                                x: vanished.x,
+                                y: y as u8,
+                            });
+                            occupied & (1 << cell) != 0
+                        })
+                        .count();
+                    pieces < height && vanished.y() + pieces + 1 >= height
+                } else {
+                    occupied & (1 << game.setting.cell(vanished)) == 0
+                };
+                if !vacant {
+                    return Err(StateError::InvalidHistory);
                 }
             }
+            game.undo();
+            game.verify_full()?;
+            if game.full_scan_result().is_win() {
+                return Err(StateError::InvalidHistory);
+            }
         }
-        // should not take out of board
-        for take in self.state.takes.iter() {
-            if take.x() >= self.setting.board_width() || take.y() >= self.setting.board_height() {
-                return Err(format!("Should not take out of board {:?}", take));
+        let mut game = self.clone();
+        while let Some(&take) = game.undone.last() {
+            if game.is_finished() || game.validate_take(take).is_err() {
+                return Err(StateError::InvalidHistory);
+            }
+            game.redo();
+        }
+        Ok(())
+    }
+
+    pub fn verify_full(&self) -> Result<(), StateError> {
+        self.state().verify(&self.setting)?;
+        for first in [true, false] {
+            let takes = if first {
+                self.steps_taken.div_ceil(2)
+            } else {
+                self.steps_taken / 2
+            };
+            let expected = takes.min(self.setting.player_piece_limit(first));
+            let len = self.board.player_len(first);
+            if len != expected {
+                return Err(StateError::PieceCount {
+                    first,
+                    steps_taken: self.steps_taken,
+                    expected,
+                    len,
+                });
             }
         }
-        // both should not win at the same time
-        if self.is_win(true) && self.is_win(false) {
-            return Err("Both should not win at the same time".to_string());
+        // both should not win at the same time, unless pieces fell under gravity
+        if !self.setting.is_gravity() && self.is_win(true) && self.is_win(false) {
+            return Err(StateError::BothWin);
         }
         Ok(())
     }
 
-    pub fn normalize(&self) -> Game {
-        let mut new_game = self.clone();
-        new_game.state = self.state.normalized(&self.setting);
-        new_game
+    pub fn normalize(&self) -> Game<R> {
+        self.normalize_with_symmetry().0
     }
 
     pub fn is_normalized(&self) -> bool {
-        self.state.is_normalized(&self.setting)
+        self.normalized_state().0 == *self.state()
+    }
+
+    /// Returns the normalized game together with the symmetry that maps this game onto it.
+    pub fn normalize_with_symmetry(&self) -> (Game<R>, Transform) {
+        let (state, symmetry) = self.normalized_state();
+        let mut new_game = self.clone();
+        new_game.set_state(&state, self.steps_taken);
+        (new_game, symmetry)
     }
 
     pub fn is_last_take(&self, take: Take) -> bool {
-        self.state.takes.last() == Some(&take)
+        take.x() < self.setting.board_width()
+            && take.y() < self.setting.board_height()
+            && self.pieces().last().map(|&(last, _, _)| last) == Some(take)
+    }
+
+    /// The state normalized among the symmetries of the rules.
+    fn normalized_state(&self) -> (BoardState, Transform) {
+        self.state()
+            .normalized_among(&self.setting, &self.geometry.symmetries)
+    }
+
+    pub fn analysis_key(&self) -> AnalysisKey {
+        AnalysisKey {
+            state: self.normalized_state().0,
+            is_next_first: self.is_next_first(),
+        }
     }
 }
-impl fmt::Display for Game {
+impl<R: Ruleset> fmt::Display for Game<R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(
+        write!(
             f,
-            "TicTacToeGame {}x{} (height x width) with {} pieces",
+            "TicTacToeGame {}x{} (height x width) with {} pieces and goal {}",
             self.setting.board_height(),
             self.setting.board_width(),
-            self.setting.piece_limit
+            self.setting.piece_limits_text(),
+            self.setting.goal
         )?;
+        for option in self.setting.options() {
+            write!(f, ", {}", option)?;
+        }
+        writeln!(f)?;
+        let separator = "-".repeat(self.setting.board_width() * 6 + 1);
         let cells = self.to_cells();
-        for row in cells.iter() {
-            for _ in 0..self.setting.board_width() {
-                write!(f, "------")?;
-            }
-            writeln!(f, "-")?;
-            for cell in row.iter() {
+        let winning_takes = self
+            .winning_lines()
+            .into_iter()
+            .flat_map(|line| line.takes)
+            .collect::<Vec<_>>();
+        for (y, row) in cells.iter().enumerate() {
+            writeln!(f, "{}", separator)?;
+            for (x, cell) in row.iter().enumerate() {
+                let take = Take {
+                    x: x as u8,
+                    y: y as u8,
+                };
+                let (first, second) = if winning_takes.contains(&take) {
+                    ('O', 'X')
+                } else {
+                    ('o', 'x')
+                };
                 write!(
                     f,
                     "| {} ",
                     match cell {
-                        CellView::None => " ".to_string(),
-                        CellView::First(n) => format!("o{: <2}", n),
-                        CellView::Second(n) => format!("x{: <2}", n),
+                        CellView::None => "   ".to_string(),
+                        CellView::First(n) => format!("{}{: <2}", first, n),
+                        CellView::Second(n) => format!("{}{: <2}", second, n),
                     }
                 )?;
             }
             writeln!(f, "|")?;
         }
-        writeln!(f, "----------------")?;
+        writeln!(f, "{}", separator)?;
         writeln!(
             f,
             "0 is about to disappear, o is the first player, x is the second player"
         )?;
+        if !winning_takes.is_empty() {
+            writeln!(f, "O and X are pieces in a completed line")?;
+        }
+        if let Some(piece) = self.next_vanishing() {
+            writeln!(
+                f,
+                "{} at {} vanishes with the next take",
+                if piece.is_first { 'o' } else { 'x' },
+                piece.take
+            )?;
+        }
         writeln!(f, "{} steps taken", self.steps_taken)?;
         Ok(())
     }
 }
+/// Parses the diagram written by `Display`. Lines other than the header, the rows and the
+/// steps are ignored, as is the case of the pieces, so the diagram can be indented or trimmed
+/// down to those lines.
+impl FromStr for Game {
+    type Err = ParsePositionError;
+
+    fn from_str(s: &str) -> Result<Game, ParsePositionError> {
+        let mut lines = s.lines().map(str::trim).filter(|line| !line.is_empty());
+        let header = lines.next().unwrap_or_default();
+        let setting = parse_diagram_header(header)
+            .ok_or_else(|| ParsePositionError::InvalidHeader(header.to_string()))?
+            .map_err(|e| ParsePositionError::Setting(ParseSettingError::Setting(e)))?;
+        let mut cells = vec![];
+        let mut steps_taken = None;
+        for line in lines {
+            if let Some(row) = line.strip_prefix('|') {
+                let row = row
+                    .strip_suffix('|')
+                    .ok_or_else(|| ParsePositionError::InvalidRow(line.to_string()))?;
+                cells.push(
+                    row.split('|')
+                        .map(|cell| parse_diagram_cell(cell.trim()))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| ParsePositionError::InvalidRow(line.to_string()))?,
+                );
+            } else if let Some(steps) = line.strip_suffix(" steps taken") {
+                steps_taken = Some(
+                    steps
+                        .parse::<usize>()
+                        .map_err(|_| ParsePositionError::InvalidSteps(steps.to_string()))?,
+                );
+            }
+        }
+        let steps_taken = steps_taken.ok_or(ParsePositionError::MissingSteps)?;
+        let state = BoardState::from_cells(&setting, &cells, steps_taken)
+            .ok_or(ParsePositionError::InvalidPieces)?;
+        let mut game = Game::new(setting);
+        game.try_replace_state(state, steps_taken)
+            .map_err(ParsePositionError::State)?;
+        Ok(game)
+    }
+}
+
+/// Reads the setting from the first line of a diagram. Returns `None` if the line is not a
+/// diagram header.
+fn parse_diagram_header(line: &str) -> Option<Result<GameSetting, SettingError>> {
+    let rest = line.strip_prefix("TicTacToeGame ")?;
+    let (size, rest) = rest.split_once(" (height x width) with ")?;
+    let (piece_limit, rest) = rest.split_once(" pieces and goal ")?;
+    let (board_height, board_width) = size.split_once('x')?;
+    let (piece_limit, second_piece_limit) = parse_piece_limits(piece_limit)?;
+    let mut parts = rest.split(", ");
+    let setting = match GameSetting::try_new_asymmetric(
+        board_height.parse().ok()?,
+        board_width.parse().ok()?,
+        parts.next()?.parse().ok()?,
+        piece_limit,
+        second_piece_limit,
+    ) {
+        Ok(setting) => setting,
+        Err(e) => return Some(Err(e)),
+    };
+    Some(Ok(parts.try_fold(setting, |setting, option| {
+        setting.with_option(option)
+    })?))
+}
+
+/// What a `Game` is serialized as, versioned as `DictionarySave` is.
+#[derive(Serialize, Deserialize)]
+enum GameSave {
+    V1 {
+        setting: GameSetting,
+        state: BoardState,
+        steps_taken: usize,
+        history: Vec<Take>,
+        vanished: Vec<Option<Take>>,
+        undone: Vec<Take>,
+    },
+}
+impl From<Game> for GameSave {
+    fn from(game: Game) -> GameSave {
+        GameSave::V1 {
+            setting: game.setting,
+            state: game.state().clone(),
+            steps_taken: game.steps_taken,
+            history: game.history,
+            vanished: game.vanished,
+            undone: game.undone,
+        }
+    }
+}
+impl TryFrom<GameSave> for Game {
+    type Error = StateError;
+
+    fn try_from(save: GameSave) -> Result<Game, StateError> {
+        let GameSave::V1 {
+            setting,
+            state,
+            steps_taken,
+            history,
+            vanished,
+            undone,
+        } = save;
+        let mut game = Game::new(setting);
+        game.try_replace_state(state, steps_taken)?;
+        game.history = history;
+        game.vanished = vanished;
+        game.undone = undone;
+        game.verify_history()?;
+        Ok(game)
+    }
+}
+impl Serialize for Game {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        GameSave::from(self.clone()).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for Game {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Game, D::Error> {
+        Game::try_from(GameSave::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+fn parse_diagram_cell(cell: &str) -> Option<CellView> {
+    if cell.is_empty() {
+        return Some(CellView::None);
+    }
+    let mut chars = cell.chars();
+    let piece = chars.next()?;
+    let rest = chars.as_str().parse().ok()?;
+    match piece {
+        'o' | 'O' => Some(CellView::First(rest)),
+        'x' | 'X' => Some(CellView::Second(rest)),
+        _ => None,
+    }
+}
+
+/// A whole game played from the empty board, with optional metadata. It is written in a
+/// PGN-like format: one `[Name "value"]` header per line, then the move list, e.g.
+///
+/// ```text
+/// [Height "3"]
+/// [Width "3"]
+/// [Goal "3"]
+/// [PieceLimit "3"]
+/// [First "Alice"]
+/// [Result "*"]
+///
+/// b2 a1 c3
+/// ```
+///
+/// `Options` lists the options of the setting separated by commas, e.g. `misere`, and is left
+/// out when there are none. `Result` is `1-0` when the first player won, `0-1` when the
+/// second player won and `*` otherwise. Unknown headers are ignored when parsing.
+#[derive(Debug, Clone)]
+pub struct GameRecord {
+    game: Game,
+    pub first: Option<String>,
+    pub second: Option<String>,
+    pub date: Option<String>,
+}
+impl GameRecord {
+    /// Returns `None` if the history of the game does not start from the empty board.
+    pub fn new(game: Game) -> Option<GameRecord> {
+        let mut replayed = Game::new(game.setting);
+        for &take in game.history() {
+            replayed.add_take(take);
+        }
+        if replayed.steps_taken() != game.steps_taken() || replayed.state() != game.state() {
+            return None;
+        }
+        Some(GameRecord {
+            game,
+            first: None,
+            second: None,
+            date: None,
+        })
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn into_game(self) -> Game {
+        self.game
+    }
+}
+impl fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let setting = &self.game.setting;
+        let result = match self.game.result() {
+            GameResult::FirstWin => "1-0",
+            GameResult::SecondWin => "0-1",
+            GameResult::Continue => "*",
+        };
+        let headers = [
+            ("Height", Some(setting.board_height().to_string())),
+            ("Width", Some(setting.board_width().to_string())),
+            ("Goal", Some(setting.goal().to_string())),
+            ("PieceLimit", Some(setting.piece_limits_text())),
+            (
+                "Options",
+                Some(setting.options().join(", ")).filter(|options| !options.is_empty()),
+            ),
+            ("First", self.first.clone()),
+            ("Second", self.second.clone()),
+            ("Date", self.date.clone()),
+            ("Result", Some(result.to_string())),
+        ];
+        for (name, value) in headers {
+            if let Some(value) = value {
+                writeln!(
+                    f,
+                    "[{} \"{}\"]",
+                    name,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.game.move_list())
+    }
+}
+impl FromStr for GameRecord {
+    type Err = ParseRecordError;
+
+    /// Parses the record and replays its moves, reporting the first line that cannot be read
+    /// or holds an illegal take.
+    fn from_str(s: &str) -> Result<GameRecord, ParseRecordError> {
+        let error = |line: usize, kind: RecordErrorKind| ParseRecordError { line, kind };
+        let mut headers = HashMap::<String, (usize, String)>::new();
+        let mut lines = s.lines().enumerate().map(|(i, text)| (i + 1, text.trim()));
+        let mut moves_line = s.lines().count() + 1;
+        let mut moves = vec![];
+        for (line, text) in lines.by_ref() {
+            if text.is_empty() {
+                continue;
+            }
+            if !text.starts_with('[') {
+                moves_line = line;
+                moves.push((line, text));
+                break;
+            }
+            let (name, value) =
+                parse_header(text).ok_or(error(line, RecordErrorKind::InvalidHeader))?;
+            if headers.insert(name.clone(), (line, value)).is_some() {
+                return Err(error(line, RecordErrorKind::DuplicateHeader(name)));
+            }
+        }
+        for (line, text) in lines {
+            if text.starts_with('[') {
+                return Err(error(line, RecordErrorKind::HeaderAfterMoves));
+            }
+            moves.push((line, text));
+        }
+
+        let header = |name: &'static str| {
+            headers
+                .get(name)
+                .map(|(_, value)| value.as_str())
+                .ok_or(error(moves_line, RecordErrorKind::MissingHeader(name)))
+        };
+        let invalid_value = |name: &'static str| {
+            let (line, value) = &headers[name];
+            error(
+                *line,
+                RecordErrorKind::InvalidValue {
+                    name: name.to_string(),
+                    value: value.clone(),
+                },
+            )
+        };
+        let number = |name: &'static str| -> Result<usize, ParseRecordError> {
+            header(name)?.parse().map_err(|_| invalid_value(name))
+        };
+        let (piece_limit, second_piece_limit) =
+            parse_piece_limits(header("PieceLimit")?).ok_or_else(|| invalid_value("PieceLimit"))?;
+        let setting = GameSetting::try_new_asymmetric(
+            number("Height")?,
+            number("Width")?,
+            number("Goal")?,
+            piece_limit,
+            second_piece_limit,
+        )
+        .map_err(|e| {
+            // Report the error at the header holding the value that is out of range.
+            let name = match e {
+                SettingError::BoardHeightOutOfRange(_) => "Height",
+                SettingError::BoardWidthOutOfRange(_) => "Width",
+                SettingError::GoalOutOfRange { .. } => "Goal",
+                SettingError::PieceLimitOutOfRange(_) => "PieceLimit",
+            };
+            error(headers[name].0, RecordErrorKind::Setting(e))
+        })?;
+        let setting = match headers.get("Options") {
+            Some((_, options)) => options
+                .split(',')
+                .map(str::trim)
+                .filter(|option| !option.is_empty())
+                .try_fold(setting, |setting, option| setting.with_option(option))
+                .ok_or_else(|| invalid_value("Options"))?,
+            None => setting,
+        };
+
+        let mut game = Game::new(setting);
+        for (line, text) in moves {
+            let move_list =
+                MoveList::from_str(text).map_err(|e| error(line, RecordErrorKind::Take(e)))?;
+            for take in move_list.0 {
+                if game.is_finished() {
+                    return Err(error(line, RecordErrorKind::AfterFinish(take)));
+                }
+                game.validate_take(take)
+                    .map_err(|e| error(line, RecordErrorKind::IllegalTake(e)))?;
+                game.add_take(take);
+            }
+        }
+
+        if let Some((line, value)) = headers.get("Result") {
+            let result = match value.as_str() {
+                "1-0" => GameResult::FirstWin,
+                "0-1" => GameResult::SecondWin,
+                "*" => GameResult::Continue,
+                _ => {
+                    return Err(error(
+                        *line,
+                        RecordErrorKind::InvalidValue {
+                            name: "Result".to_string(),
+                            value: value.clone(),
+                        },
+                    ))
+                }
+            };
+            if result != game.result() {
+                return Err(error(*line, RecordErrorKind::ResultMismatch));
+            }
+        }
+
+        let mut text = |name: &str| headers.remove(name).map(|(_, value)| value);
+        Ok(GameRecord {
+            first: text("First"),
+            second: text("Second"),
+            date: text("Date"),
+            game,
+        })
+    }
+}
+
+/// Splits `[Name "value"]` into its name and unescaped value.
+fn parse_header(text: &str) -> Option<(String, String)> {
+    let inner = text.strip_prefix('[')?.strip_suffix(']')?;
+    let (name, quoted) = inner.split_once(' ')?;
+    let quoted = quoted.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            '"' => return None,
+            c => value.push(c),
+        }
+    }
+    if name.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), value))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseRecordError {
+    /// 1-based line the error was found on.
+    pub line: usize,
+    pub kind: RecordErrorKind,
+}
+impl fmt::Display for ParseRecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.kind)
+    }
+}
+impl std::error::Error for ParseRecordError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordErrorKind {
+    InvalidHeader,
+    DuplicateHeader(String),
+    HeaderAfterMoves,
+    MissingHeader(&'static str),
+    InvalidValue { name: String, value: String },
+    Setting(SettingError),
+    Take(ParseTakeError),
+    IllegalTake(TakeError),
+    AfterFinish(Take),
+    ResultMismatch,
+}
+impl fmt::Display for RecordErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordErrorKind::InvalidHeader => write!(f, "Header should be [Name \"value\"]"),
+            RecordErrorKind::DuplicateHeader(name) => write!(f, "Duplicate header {}", name),
+            RecordErrorKind::HeaderAfterMoves => write!(f, "Header after the moves"),
+            RecordErrorKind::MissingHeader(name) => write!(f, "Missing header {}", name),
+            RecordErrorKind::InvalidValue { name, value } => {
+                write!(f, "Invalid value for {}: {:?}", name, value)
+            }
+            RecordErrorKind::Setting(e) => write!(f, "{}", e),
+            RecordErrorKind::Take(e) => write!(f, "{}", e),
+            RecordErrorKind::IllegalTake(e) => write!(f, "{}", e),
+            RecordErrorKind::AfterFinish(take) => {
+                write!(f, "Take {} after the game finished", take)
+            }
+            RecordErrorKind::ResultMismatch => {
+                write!(f, "Result does not match the replayed game")
+            }
+        }
+    }
+}
+
+/// Error from `Game::from_position` or from parsing a board diagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsePositionError {
+    InvalidFormat(String),
+    Setting(ParseSettingError),
+    InvalidRow(String),
+    InvalidSide(String),
+    InvalidSteps(String),
+    InvalidHeader(String),
+    MissingSteps,
+    SideMismatch,
+    InvalidPieces,
+    State(StateError),
+}
+impl fmt::Display for ParsePositionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParsePositionError::InvalidFormat(s) => write!(
+                f,
+                "Position should be setting, rows, side and steps separated by spaces, but {:?}",
+                s
+            ),
+            ParsePositionError::Setting(e) => write!(f, "{}", e),
+            ParsePositionError::InvalidRow(row) => write!(f, "Invalid row {:?}", row),
+            ParsePositionError::InvalidSide(side) => {
+                write!(f, "Side should be o or x, but {:?}", side)
+            }
+            ParsePositionError::InvalidSteps(steps) => write!(f, "Invalid steps {:?}", steps),
+            ParsePositionError::InvalidHeader(header) => {
+                write!(f, "Invalid diagram header {:?}", header)
+            }
+            ParsePositionError::MissingSteps => write!(f, "Missing steps taken"),
+            ParsePositionError::SideMismatch => {
+                write!(f, "Side to move does not match the steps taken")
+            }
+            ParsePositionError::InvalidPieces => write!(
+                f,
+                "Pieces do not fit the board or their numbers cannot be put in order"
+            ),
+            ParsePositionError::State(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for ParsePositionError {}
 
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub enum GameAnalysis {
     Winning(usize),
     Losing(usize),
+    Draw,
     #[default]
-    Neutral,
+    Unknown,
 }
 impl GameAnalysis {
     pub fn is_winning(&self) -> bool {
@@ -466,8 +2894,11 @@ impl GameAnalysis {
     pub fn is_losing(&self) -> bool {
         matches!(self, GameAnalysis::Losing(_))
     }
-    pub fn is_neutral(&self) -> bool {
-        matches!(self, GameAnalysis::Neutral)
+    pub fn is_draw(&self) -> bool {
+        matches!(self, GameAnalysis::Draw)
+    }
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, GameAnalysis::Unknown)
     }
     pub fn max() -> GameAnalysis {
         GameAnalysis::Losing(0)
@@ -475,13 +2906,24 @@ impl GameAnalysis {
     pub fn min() -> GameAnalysis {
         GameAnalysis::Winning(0)
     }
+
+    /// Swaps winning and losing, turning the analysis of a position into the outcome for the
+    /// player who moved into it.
+    pub fn flip(&self) -> GameAnalysis {
+        match self {
+            GameAnalysis::Winning(n) => GameAnalysis::Losing(*n),
+            GameAnalysis::Losing(n) => GameAnalysis::Winning(*n),
+            analysis => analysis.clone(),
+        }
+    }
 }
 impl fmt::Display for GameAnalysis {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             GameAnalysis::Winning(n) => write!(f, "Winning in {} steps", n),
             GameAnalysis::Losing(n) => write!(f, "Losing in {} steps", n),
-            GameAnalysis::Neutral => write!(f, "Neutral"),
+            GameAnalysis::Draw => write!(f, "Draw"),
+            GameAnalysis::Unknown => write!(f, "Unknown"),
         }
     }
 }
@@ -500,32 +2942,162 @@ impl Ord for GameAnalysis {
             (GameAnalysis::Losing(_), _) => std::cmp::Ordering::Greater,
             (_, GameAnalysis::Winning(_)) => std::cmp::Ordering::Greater,
             (_, GameAnalysis::Losing(_)) => std::cmp::Ordering::Less,
+            (GameAnalysis::Draw, GameAnalysis::Unknown) => std::cmp::Ordering::Less,
+            (GameAnalysis::Unknown, GameAnalysis::Draw) => std::cmp::Ordering::Greater,
             _ => std::cmp::Ordering::Equal,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AnalysisKey {
+    state: BoardState,
+    is_next_first: bool,
+}
+impl AnalysisKey {
+    pub fn new(state: BoardState, is_next_first: bool) -> AnalysisKey {
+        AnalysisKey {
+            state,
+            is_next_first,
+        }
+    }
+    pub fn state(&self) -> &BoardState {
+        &self.state
+    }
+    pub fn is_next_first(&self) -> bool {
+        self.is_next_first
+    }
+}
+
 use std::collections::VecDeque;
 use std::collections::{HashMap, HashSet};
-pub fn analyze(setting: GameSetting, start: BoardState, max_cnt: usize) -> AnalysisDictionary {
-    let mut game = Game::new(setting);
-    game.replace_state(start, 0);
-    game = game.normalize();
-
-    let mut visited = HashSet::new();
-    let mut valid_take_count = HashMap::<BoardState, usize>::new();
-    let mut edges = HashMap::<BoardState, Vec<BoardState>>::new();
-    let mut edges_rev = HashMap::<BoardState, Vec<BoardState>>::new();
-    //let mut stack = vec![];
-    let mut search = VecDeque::new();
-    visited.insert(game.state.clone());
-    //stack.push(game.state.clone());
-    search.push_back(game.state.clone());
 
-    let mut queue = VecDeque::new();
+/// Settings with at most this many `StateIndexer` keys are searched with positions numbered
+/// by a `CanonicalStateIndexer`. Building one for more keys would take longer than the search.
+const MAX_RANKED_KEYS: u64 = 1 << 27;
+
+/// Numbers the positions met while searching.
+enum PositionIds {
+    /// The positions are numbered densely up to symmetry, and the ones met are marked.
+    Ranked {
+        indexer: CanonicalStateIndexer,
+        met: Vec<bool>,
+    },
+    /// The positions are numbered in the order they are met and told apart by their
+    /// canonical 128-bit Zobrist hash, so that no position is normalized during the search.
+    /// Distinct positions share a hash with a negligible probability.
+    Hashed {
+        by_zobrist: HashMap<u128, usize>,
+        /// Each position as it was first met, not normalized.
+        keys: Vec<AnalysisKey>,
+    },
+}
+impl PositionIds {
+    fn new<R: Ruleset>(game: &Game<R>) -> PositionIds {
+        let ranked = StateIndexer::try_new(game.setting)
+            .filter(|indexer| indexer.len() <= MAX_RANKED_KEYS)
+            .and_then(|_| {
+                CanonicalStateIndexer::try_with_symmetries(game.setting, &game.geometry.symmetries)
+            });
+        match ranked {
+            Some(indexer) => PositionIds::Ranked {
+                met: vec![false; indexer.len() as usize],
+                indexer,
+            },
+            None => PositionIds::Hashed {
+                by_zobrist: HashMap::new(),
+                keys: vec![],
+            },
+        }
+    }
+
+    /// The number of ids given so far, or that can be given when they are ranked.
+    fn len(&self) -> usize {
+        match self {
+            PositionIds::Ranked { met, .. } => met.len(),
+            PositionIds::Hashed { keys, .. } => keys.len(),
+        }
+    }
+
+    /// Returns the id of the position and whether it was met for the first time.
+    fn id<R: Ruleset>(&mut self, game: &Game<R>) -> (usize, bool) {
+        match self {
+            PositionIds::Ranked { indexer, met } => {
+                let cells = game
+                    .pieces()
+                    .into_iter()
+                    .map(|(take, _, _)| game.setting.cell(take))
+                    .collect::<Vec<_>>();
+                let id = indexer
+                    .rank_cells(&cells, game.is_next_first())
+                    .expect("Positions reached should be numbered")
+                    as usize;
+                (id, !std::mem::replace(&mut met[id], true))
+            }
+            PositionIds::Hashed { by_zobrist, keys } => {
+                let next = keys.len();
+                let id = *by_zobrist
+                    .entry(game.canonical_wide_zobrist())
+                    .or_insert(next);
+                if id != next {
+                    return (id, false);
+                }
+                keys.push(AnalysisKey {
+                    state: game.state().clone(),
+                    is_next_first: game.is_next_first(),
+                });
+                (id, true)
+            }
+        }
+    }
+
+    /// The position the id was given to, if it was met, not necessarily normalized.
+    fn key(&self, id: usize) -> Option<AnalysisKey> {
+        match self {
+            PositionIds::Ranked { indexer, met } => {
+                met[id].then(|| indexer.unrank(id as u64).unwrap())
+            }
+            PositionIds::Hashed { keys, .. } => Some(keys[id].clone()),
+        }
+    }
+
+    /// The normalized key of the position the id was given to, if it was met. Hashed
+    /// positions are normalized here, once the search is over.
+    fn analysis_key<R: Ruleset>(&self, id: usize, game: &Game<R>) -> Option<AnalysisKey> {
+        let key = self.key(id)?;
+        Some(match self {
+            PositionIds::Ranked { .. } => key,
+            PositionIds::Hashed { .. } => AnalysisKey {
+                state: key
+                    .state
+                    .normalized_among(&game.setting, &game.geometry.symmetries)
+                    .0,
+                is_next_first: key.is_next_first,
+            },
+        })
+    }
+}
+
+pub fn analyze<R: Ruleset>(start: Game<R>, max_cnt: usize) -> AnalysisDictionary {
+    let setting = start.setting;
+
+    // Everything below is indexed by position id.
+    let mut ids = PositionIds::new(&start);
+    let mut searched = vec![];
+    let mut edges = vec![];
+    let mut edges_rev = vec![];
+    let mut terminal = vec![];
+    // Only ids are queued, so that the queue stays small, and each position is rebuilt from
+    // its key when it is searched.
+    let mut search = VecDeque::new();
+    let base = start.without_history();
+    search.push_back(ids.id(&start).0);
+    searched.resize(ids.len(), false);
+    edges.resize_with(ids.len(), Vec::new);
+    edges_rev.resize_with(ids.len(), Vec::new);
 
     let mut cnt = 0;
-    while let Some(state) = search.pop_front() {
+    while let Some(id) = search.pop_front() {
         cnt += 1;
         if cnt % 10000 == 0 {
             println!("cnt: {}", cnt);
@@ -533,120 +3105,267 @@ pub fn analyze(setting: GameSetting, start: BoardState, max_cnt: usize) -> Analy
         if cnt > max_cnt {
             break;
         }
-        game.replace_state(state.clone(), 1);
-        if game.result() == GameResult::FirstWin {
-            queue.push_back(state.clone());
-            valid_take_count.insert(state.clone(), 0);
+        let mut game = base.clone();
+        let key = ids.key(id).unwrap();
+        game.set_state(&key.state, if key.is_next_first { 0 } else { 1 });
+        let result = game.result();
+        if result.is_win() {
+            // The rules decide who wins by a line, which in misere is the player to move, and
+            // a start position may be given with either player already having a line.
+            let analysis = if (result == GameResult::FirstWin) == game.is_next_first() {
+                GameAnalysis::Winning(0)
+            } else {
+                GameAnalysis::Losing(0)
+            };
+            terminal.push((id, analysis));
             continue;
         }
-        valid_take_count.insert(state.clone(), game.valid_take_count());
-        for y in 0..game.setting.board_height() {
-            for x in 0..game.setting.board_width() {
-                let take = Take {
-                    x: x as u8,
-                    y: y as u8,
-                };
-                if game.validate_take(take).is_ok() {
-                    let mut new_game = game.clone();
-                    new_game.add_take(take);
-                    let new_game = new_game.normalize();
-                    let new_state = new_game.state;
-                    if visited.insert(new_state.clone()) {
-                        search.push_back(new_state.clone());
-                    }
-                    edges
-                        .entry(state.clone())
-                        .or_default()
-                        .push(new_state.clone());
-                    edges_rev
-                        .entry(new_state.clone())
-                        .or_default()
-                        .push(state.clone());
-                }
+        for (_, new_game) in game.successors(false) {
+            let (new_id, is_new) = ids.id(&new_game);
+            if is_new {
+                search.push_back(new_id);
+                searched.resize(ids.len(), false);
+                edges.resize_with(ids.len(), Vec::new);
+                edges_rev.resize_with(ids.len(), Vec::new);
+            }
+            // Takes leading to the same position up to symmetry make a single edge.
+            if !edges[id].contains(&new_id) {
+                edges[id].push(new_id);
+                edges_rev[new_id].push(id);
             }
         }
+        searched[id] = true;
     }
 
-    let mut done = HashMap::<BoardState, GameAnalysis>::new();
+    let mut done = vec![None; ids.len()];
+    let mut queue = VecDeque::new();
+    for (id, analysis) in terminal {
+        queue.extend(edges_rev[id].iter().copied());
+        done[id] = Some(analysis);
+    }
 
     let mut cnt = 0;
-    while let Some(state) = queue.pop_front() {
+    while let Some(id) = queue.pop_front() {
         cnt += 1;
         if cnt % 10000 == 0 {
             println!("cnt: {}", cnt);
         }
-        let valid_take_count = valid_take_count.get(&state).copied().unwrap();
-        let mut all_done = valid_take_count == edges.get(&state).map(|v| v.len()).unwrap_or(0);
+        let mut all_done = true;
         let mut winning = false;
         let mut min_to_win = usize::MAX;
         let mut max_to_lose = 0;
-        if let Some(next_states) = edges.get(&state) {
-            for next_state in next_states {
-                match done.get(next_state) {
-                    Some(GameAnalysis::Winning(to_win)) => {
-                        max_to_lose = max_to_lose.max(to_win + 1);
-                    }
-                    Some(GameAnalysis::Losing(to_lose)) => {
-                        winning = true;
-                        min_to_win = min_to_win.min(to_lose + 1);
-                    }
-                    _ => {
-                        all_done = false;
-                    }
+        for &next_id in &edges[id] {
+            match done[next_id] {
+                Some(GameAnalysis::Winning(to_win)) => {
+                    max_to_lose = max_to_lose.max(to_win + 1);
+                }
+                Some(GameAnalysis::Losing(to_lose)) => {
+                    winning = true;
+                    min_to_win = min_to_win.min(to_lose + 1);
+                }
+                _ => {
+                    all_done = false;
                 }
             }
         }
         let updated = if winning {
-            let to_update = match done.get(&state) {
-                Some(GameAnalysis::Winning(to_win)) => *to_win > min_to_win,
+            let to_update = match done[id] {
+                Some(GameAnalysis::Winning(to_win)) => to_win > min_to_win,
                 _ => true,
             };
             if to_update {
-                done.insert(state.clone(), GameAnalysis::Winning(min_to_win));
+                done[id] = Some(GameAnalysis::Winning(min_to_win));
             }
             to_update
         } else if all_done {
-            let to_update = match done.get(&state) {
-                Some(GameAnalysis::Losing(to_lose)) => *to_lose < max_to_lose,
+            let to_update = match done[id] {
+                Some(GameAnalysis::Losing(to_lose)) => to_lose < max_to_lose,
                 _ => true,
             };
             if to_update {
-                done.insert(state.clone(), GameAnalysis::Losing(max_to_lose));
+                done[id] = Some(GameAnalysis::Losing(max_to_lose));
             }
             to_update
         } else {
             false
         };
         if updated {
-            if let Some(prev_states) = edges_rev.get(&state) {
-                for prev_state in prev_states {
-                    queue.push_back(prev_state.clone());
-                }
-            }
+            queue.extend(edges_rev[id].iter().copied());
         }
     }
 
-    AnalysisDictionary {
-        setting,
-        analysis: done,
+    // Whatever propagation left unresolved is a draw, unless the search was cut off before
+    // reaching everything that state depends on.
+    let mut unknown = (0..ids.len())
+        .map(|id| !searched[id] && done[id].is_none())
+        .collect::<Vec<_>>();
+    let mut stack = (0..ids.len()).filter(|&id| unknown[id]).collect::<Vec<_>>();
+    while let Some(id) = stack.pop() {
+        for &prev_id in &edges_rev[id] {
+            if done[prev_id].is_none() && !unknown[prev_id] {
+                unknown[prev_id] = true;
+                stack.push(prev_id);
+            }
+        }
     }
+
+    let analysis = (0..ids.len())
+        .filter_map(|id| {
+            let analysis = match done[id].take() {
+                Some(analysis) => analysis,
+                None if unknown[id] => GameAnalysis::Unknown,
+                None => GameAnalysis::Draw,
+            };
+            Some((ids.analysis_key(id, &base)?, analysis))
+        })
+        .collect();
+
+    AnalysisDictionary { setting, analysis }
 }
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// Serialized through a versioned save.
+#[derive(Debug, Clone, PartialEq)]
 pub struct AnalysisDictionary {
     setting: GameSetting,
-    analysis: HashMap<BoardState, GameAnalysis>,
+    analysis: HashMap<AnalysisKey, GameAnalysis>,
+}
+
+/// What an `AnalysisDictionary` is serialized as. Add a variant instead of changing an existing
+/// one, so that dictionaries already written can still be loaded. Dictionaries are written as
+/// `V2` when the keys of the setting can be numbered by a `StateIndexer`.
+#[derive(Serialize, Deserialize)]
+enum DictionarySave {
+    V1 {
+        setting: GameSetting,
+        analysis: HashMap<AnalysisKey, GameAnalysis>,
+    },
+    /// The keys by their `StateIndexer` ranks in increasing order, each written as the gap
+    /// from the previous rank to keep the variable-length integers short.
+    V2 {
+        setting: GameSetting,
+        rank_gaps: Vec<u64>,
+        analysis: Vec<GameAnalysis>,
+    },
+}
+impl From<&AnalysisDictionary> for DictionarySave {
+    fn from(dictionary: &AnalysisDictionary) -> DictionarySave {
+        let setting = dictionary.setting;
+        let ranked = StateIndexer::try_new(setting).and_then(|indexer| {
+            dictionary
+                .analysis
+                .iter()
+                .map(|(key, analysis)| Some((indexer.rank(key)?, analysis.clone())))
+                .collect::<Option<Vec<_>>>()
+        });
+        let Some(mut ranked) = ranked else {
+            return DictionarySave::V1 {
+                setting,
+                analysis: dictionary.analysis.clone(),
+            };
+        };
+        ranked.sort_unstable_by_key(|&(rank, _)| rank);
+        let mut previous = 0;
+        let rank_gaps = ranked
+            .iter()
+            .map(|&(rank, _)| rank - std::mem::replace(&mut previous, rank))
+            .collect();
+        DictionarySave::V2 {
+            setting,
+            rank_gaps,
+            analysis: ranked.into_iter().map(|(_, analysis)| analysis).collect(),
+        }
+    }
+}
+impl TryFrom<DictionarySave> for AnalysisDictionary {
+    type Error = &'static str;
+
+    fn try_from(save: DictionarySave) -> Result<AnalysisDictionary, &'static str> {
+        match save {
+            DictionarySave::V1 { setting, analysis } => {
+                Ok(AnalysisDictionary { setting, analysis })
+            }
+            DictionarySave::V2 {
+                setting,
+                rank_gaps,
+                analysis,
+            } => {
+                let indexer =
+                    StateIndexer::try_new(setting).ok_or("Keys of the setting cannot be ranked")?;
+                if rank_gaps.len() != analysis.len() {
+                    return Err("Ranks and analysis should be as many");
+                }
+                let mut rank = 0u64;
+                let analysis = rank_gaps
+                    .into_iter()
+                    .zip(analysis)
+                    .map(|(gap, analysis)| {
+                        rank = rank.checked_add(gap)?;
+                        Some((indexer.unrank(rank)?, analysis))
+                    })
+                    .collect::<Option<HashMap<_, _>>>()
+                    .ok_or("Rank out of range")?;
+                Ok(AnalysisDictionary { setting, analysis })
+            }
+        }
+    }
+}
+impl Serialize for AnalysisDictionary {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DictionarySave::from(self).serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for AnalysisDictionary {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<AnalysisDictionary, D::Error> {
+        AnalysisDictionary::try_from(DictionarySave::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
 }
 
 impl AnalysisDictionary {
     pub fn setting(&self) -> &GameSetting {
         &self.setting
     }
-    pub fn analysis(&self) -> &HashMap<BoardState, GameAnalysis> {
+    pub fn analysis(&self) -> &HashMap<AnalysisKey, GameAnalysis> {
         &self.analysis
     }
+    pub fn get<R: Ruleset>(&self, game: &Game<R>) -> Option<&GameAnalysis> {
+        self.analysis.get(&game.analysis_key())
+    }
+
+    /// Every legal take with the analysis of the position it leads to, as seen by the player
+    /// making the take, best first. Takes that are equally good keep the row-major order.
+    pub fn best_takes<R: Ruleset>(&self, game: &Game<R>) -> Vec<(Take, GameAnalysis)> {
+        if game.is_finished() {
+            return vec![];
+        }
+        let mut takes = game
+            .successors(false)
+            .into_iter()
+            .map(|(take, game)| (take, self.get(&game).cloned().unwrap_or_default().flip()))
+            .collect::<Vec<_>>();
+        takes.sort_by(|(_, a), (_, b)| a.cmp(b));
+        takes
+    }
+
+    /// The line of play where the winner takes the fastest win and the loser resists the
+    /// longest, each take paired with its analysis as in `best_takes`. The line stops when the
+    /// game is finished or when the best take does not lead to a decided outcome.
+    pub fn principal_variation<R: Ruleset>(&self, game: &Game<R>) -> Vec<(Take, GameAnalysis)> {
+        let mut game = game.clone();
+        let mut line = vec![];
+        while let Some((take, analysis)) = self.best_takes(&game).into_iter().next() {
+            if !analysis.is_winning() && !analysis.is_losing() {
+                break;
+            }
+            game.add_take(take);
+            line.push((take, analysis));
+        }
+        line
+    }
     pub fn merge(&self, other: AnalysisDictionary) -> AnalysisDictionary {
         let mut analysis = self.analysis.clone();
         for (k, v) in other.analysis {
@@ -659,3 +3378,952 @@ impl AnalysisDictionary {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setting(s: &str) -> GameSetting {
+        s.parse().unwrap()
+    }
+
+    /// Plays a few games from the empty board with pseudo-random valid takes, calling `visit`
+    /// after every take.
+    fn random_playouts(setting: GameSetting, mut visit: impl FnMut(&Game)) {
+        let mut seed = 1;
+        for _ in 0..20 {
+            let mut game = Game::new(setting);
+            for _ in 0..40 {
+                if game.is_finished() {
+                    break;
+                }
+                seed = splitmix64(seed);
+                let takes = game.valid_takes().collect::<Vec<_>>();
+                game.add_take(takes[seed as usize % takes.len()]);
+                visit(&game);
+            }
+        }
+    }
+
+    /// The result found by scanning every line of the board for each player, the player who
+    /// just moved first.
+    fn scanned_result(game: &Game) -> GameResult {
+        let cells = game.to_cells();
+        let mover = !game.is_next_first();
+        for first in [mover, !mover] {
+            let filled = game.geometry.lines.iter().any(|&(line, _)| {
+                game.setting.takes_in(line).into_iter().all(|take| {
+                    match cells[take.y()][take.x()] {
+                        CellView::First(_) => first,
+                        CellView::Second(_) => !first,
+                        CellView::None => false,
+                    }
+                })
+            });
+            if filled {
+                return if first != game.setting.is_misere() {
+                    GameResult::FirstWin
+                } else {
+                    GameResult::SecondWin
+                };
+            }
+        }
+        GameResult::Continue
+    }
+
+    #[test]
+    fn incremental_result_matches_full_scan() {
+        for s in [
+            "3x3:3:3",
+            "3x4:3:4/3",
+            "4x5:3:4",
+            "4x4:2:3:misere",
+            "4x4:3:4:torus",
+            "3x4:3:3:gravity",
+            "4x4:3:3:torus:gravity",
+        ] {
+            random_playouts(setting(s), |game| {
+                assert_eq!(game.result(), scanned_result(game), "{}", game.position());
+                let mut undone = game.clone();
+                undone.undo();
+                assert_eq!(undone.result(), scanned_result(&undone));
+            });
+        }
+    }
+
+    #[test]
+    fn take_errors_name_the_take() {
+        let mut game = Game::new(setting("3x3:3:3"));
+        game.add_take(Take { x: 1, y: 1 });
+        let occupied = game.validate_take(Take { x: 1, y: 1 }).unwrap_err();
+        assert_eq!(occupied, TakeError::Occupied(Take { x: 1, y: 1 }));
+        assert_eq!(occupied.to_string(), "Already taken b2");
+        let out_of_board = game.validate_take(Take { x: 3, y: 0 }).unwrap_err();
+        assert_eq!(out_of_board, TakeError::OutOfBoard(Take { x: 3, y: 0 }));
+        assert_eq!(out_of_board.to_string(), "Out of board d1");
+    }
+
+    #[test]
+    fn undo_and_redo_bring_back_vanished_pieces() {
+        for s in ["3x3:3:2", "3x3:3:2:gravity"] {
+            let mut game = Game::new(setting(s));
+            let takes = "a1 b1 a2 b2 c3 c2".parse::<MoveList>().unwrap().0;
+            let mut positions = vec![game.position()];
+            for &take in &takes {
+                game.add_take(take);
+                positions.push(game.position());
+            }
+            assert_eq!(game.history().len(), takes.len());
+            while game.can_undo() {
+                game.undo();
+                assert_eq!(game.position(), positions[game.history().len()]);
+            }
+            assert_eq!(game.position(), positions[0]);
+            assert!(game.undo().is_none());
+            while game.can_redo() {
+                game.redo();
+                assert_eq!(game.position(), positions[game.history().len()]);
+            }
+            assert_eq!(game.position(), positions[takes.len()]);
+            assert!(game.redo().is_none());
+
+            game.undo();
+            game.undo();
+            game.add_take(Take { x: 1, y: 1 });
+            assert!(!game.can_redo());
+        }
+    }
+
+    #[test]
+    fn take_outcome_tells_lines_of_the_take_from_lines_of_falling_pieces() {
+        let mut game = Game::from_position("3x3:3:3 o1o2./x1x2./... o 4").unwrap();
+        let outcome = game.add_take(Take { x: 2, y: 0 });
+        assert!(outcome.completed_line);
+        assert!(!outcome.completed_by_falling);
+        assert_eq!(outcome.result, GameResult::FirstWin);
+
+        // o at c3 vanishes and x at c2 falls onto the bottom row, completing the line of x.
+        let mut game = Game::from_position("3x3:3:3:gravity .../o2o1x2/x1x0o0 o 6").unwrap();
+        let outcome = game.add_take(Take { x: 1, y: 2 });
+        assert_eq!(outcome.take, Take { x: 1, y: 0 });
+        assert_eq!(outcome.vanished, Some(Take { x: 2, y: 2 }));
+        assert!(!outcome.completed_line);
+        assert!(outcome.completed_by_falling);
+        assert_eq!(outcome.result, GameResult::SecondWin);
+    }
+
+    #[test]
+    fn winning_lines_tell_their_direction_and_takes() {
+        let takes = |notation: &str| notation.parse::<MoveList>().unwrap().0;
+        let mut game = Game::new(setting("4x4:3:4:torus"));
+        for take in takes("c1 a2 d1 b3 a1") {
+            game.add_take(take);
+        }
+        // The row goes on across the edge from d1 to a1.
+        assert_eq!(
+            game.winning_lines(),
+            [WinningLine {
+                direction: LineDirection::Row,
+                takes: takes("a1 c1 d1"),
+            }]
+        );
+
+        // o at c3 vanishes, and x at c2 falls onto the bottom row, completing the line of x,
+        // while the new o lands on c2, completing the line of o.
+        let mut game = Game::from_position("3x3:3:3:gravity .../o1o2x2/x0x1o0 o 6").unwrap();
+        assert_eq!(game.winning_lines(), []);
+        game.add_take(Take { x: 2, y: 0 });
+        let mut lines = game.winning_lines();
+        lines.sort_by_key(|line| line.takes[0].y());
+        assert_eq!(
+            lines,
+            [
+                WinningLine {
+                    direction: LineDirection::Row,
+                    takes: takes("a2 b2 c2"),
+                },
+                WinningLine {
+                    direction: LineDirection::Row,
+                    takes: takes("a3 b3 c3"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn games_can_be_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Game>();
+        let mut game = Game::new(setting("3x3:3:3"));
+        game.add_take(Take { x: 1, y: 1 });
+        let keys = std::thread::scope(|scope| {
+            let handles = [
+                scope.spawn(|| game.analysis_key()),
+                scope.spawn(|| game.analysis_key()),
+            ];
+            handles.map(|handle| handle.join().unwrap())
+        });
+        assert_eq!(keys, [game.analysis_key(), game.analysis_key()]);
+    }
+
+    #[test]
+    fn zobrist_depends_on_side_to_move() {
+        let first_to_move = Game::new(setting("3x3:3:3"));
+        let mut second_to_move = first_to_move.clone();
+        second_to_move.set_state(&BoardState::default(), 1);
+        assert_ne!(first_to_move.zobrist(), second_to_move.zobrist());
+        assert_ne!(
+            first_to_move.canonical_zobrist(),
+            second_to_move.canonical_zobrist()
+        );
+    }
+
+    #[test]
+    fn zobrist_is_kept_up_to_date() {
+        for s in ["3x3:3:3", "3x4:3:4/3", "4x4:3:4:torus", "3x4:3:3:gravity"] {
+            random_playouts(setting(s), |game| {
+                let mut rebuilt = Game::new(game.setting);
+                rebuilt.replace_state(game.state().clone(), game.steps_taken());
+                assert_eq!(rebuilt.zobrist(), game.zobrist());
+                assert_eq!(rebuilt.canonical_zobrist(), game.canonical_zobrist());
+                assert_eq!(
+                    game.normalize().canonical_zobrist(),
+                    game.canonical_zobrist()
+                );
+
+                let mut undone = game.clone();
+                undone.undo();
+                undone.redo();
+                assert_eq!(undone.zobrist(), game.zobrist());
+            });
+        }
+    }
+
+    #[test]
+    fn symmetric_positions_share_an_id() {
+        let empty = Game::new(setting("3x3:3:3"));
+        let ranked = PositionIds::new(&empty);
+        assert!(matches!(ranked, PositionIds::Ranked { .. }));
+        let hashed = PositionIds::Hashed {
+            by_zobrist: HashMap::new(),
+            keys: vec![],
+        };
+        for mut ids in [ranked, hashed] {
+            let mut found = vec![];
+            for take in ["a1", "c1", "a3", "c3", "b2"] {
+                let mut game = empty.clone();
+                game.add_take(take.parse().unwrap());
+                found.push((ids.id(&game), game.analysis_key()));
+            }
+            let ((corner, _), corner_key) = found[0].clone();
+            let ((center, _), center_key) = found[4].clone();
+            assert_ne!(corner, center);
+            assert_eq!(
+                found.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+                [
+                    (corner, true),
+                    (corner, false),
+                    (corner, false),
+                    (corner, false),
+                    (center, true)
+                ]
+            );
+            assert_eq!(ids.analysis_key(corner, &empty), Some(corner_key));
+            assert_eq!(ids.analysis_key(center, &empty), Some(center_key));
+        }
+    }
+
+    #[test]
+    fn successors_start_without_history() {
+        let mut game = Game::new(setting("3x3:3:2"));
+        for take in ["a1", "b1", "a2", "b2"] {
+            game.add_take(take.parse().unwrap());
+        }
+        for (take, successor) in game.successors(false) {
+            let mut played = game.clone();
+            played.add_take(take);
+            assert!(successor.history().is_empty());
+            assert!(!successor.can_undo());
+            assert_eq!(successor.state(), played.state());
+            assert_eq!(successor.result(), played.result());
+            assert_eq!(successor.zobrist(), played.zobrist());
+        }
+    }
+
+    #[test]
+    fn successors_keep_one_take_per_position_up_to_symmetry() {
+        let setting = setting("3x3:3:3");
+        let game = Game::new(setting);
+        let kept = game
+            .successors(true)
+            .into_iter()
+            .map(|(take, _)| take)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, "a1 b1 b2".parse::<MoveList>().unwrap().0);
+        for (take, _) in game.successors(false) {
+            assert!(
+                Transform::all(&setting)
+                    .into_iter()
+                    .any(|transform| kept.contains(&take.apply(transform, &setting))),
+                "{}",
+                take
+            );
+        }
+    }
+
+    #[test]
+    fn game_record_round_trips() {
+        let mut game = Game::new(setting("3x4:3:4/3:torus"));
+        for take in ["b2", "a1", "c3", "a3", "d1"] {
+            game.add_take(take.parse().unwrap());
+        }
+        let mut record = GameRecord::new(game).unwrap();
+        record.first = Some("Alice \"A\"".to_string());
+        record.date = Some("2024-01-02".to_string());
+        let text = record.to_string();
+        assert!(text.contains("[Options \"torus\"]"));
+        let parsed = text.parse::<GameRecord>().unwrap();
+        assert_eq!(parsed.to_string(), text);
+        assert_eq!(parsed.game().history(), record.game().history());
+        assert_eq!(parsed.first, record.first);
+        assert_eq!(parsed.second, None);
+        assert_eq!(parsed.date, record.date);
+    }
+
+    #[test]
+    fn game_record_errors_tell_the_line() {
+        let record = |goal: &str, piece_limit: &str, rest: &str| {
+            format!(
+                "[Height \"3\"]\n[Width \"3\"]\n[Goal \"{}\"]\n[PieceLimit \"{}\"]\n{}",
+                goal, piece_limit, rest
+            )
+        };
+        let line = |text: String| text.parse::<GameRecord>().unwrap_err().line;
+        assert_eq!(line(record("3", "3", "\nb2 b2")), 6);
+        assert_eq!(line(record("3", "3", "\nb2\na1 z9")), 7);
+        assert_eq!(line(record("3", "3", "[Result \"1-0\"]\n\nb2")), 5);
+        assert_eq!(line(record("3", "3", "[Height \"4\"]\n\nb2")), 5);
+        assert_eq!(line(record("3", "3", "\nb2\n[Date \"x\"]")), 7);
+        assert_eq!(line(record("3", "3", "").replace("[Width \"3\"]\n", "")), 4);
+        assert_eq!(
+            line(record("3", "3", "").replace("Width \"3", "Width \"12")),
+            2
+        );
+        assert_eq!(line(record("4", "3", "\nb2")), 3);
+        assert_eq!(line(record("3", "0", "\nb2")), 4);
+    }
+
+    #[test]
+    fn positions_that_cannot_be_reached_are_rejected() {
+        let error = |s: &str| Game::from_position(s).unwrap_err();
+        let state_error = |s: &str| match error(s) {
+            ParsePositionError::State(e) => e,
+            e => panic!("{:?}", e),
+        };
+        assert!(Game::from_position("3x3:3:3 o1o2./x1x2./... o 4").is_ok());
+        assert!(Game::from_position("3x3:3:3 o0x0o1/x1o2x2/... o 10").is_ok());
+        assert_eq!(
+            error("3x3:3:3 o1o2./x1x2./... x 4"),
+            ParsePositionError::SideMismatch
+        );
+        assert_eq!(
+            error("3x3:3:3 o1o1./x1x2./... o 4"),
+            ParsePositionError::InvalidPieces
+        );
+        assert_eq!(
+            state_error("3x3:3:3 o1o2./x1x2./... o 2"),
+            StateError::PieceCount {
+                first: true,
+                steps_taken: 2,
+                expected: 1,
+                len: 2,
+            }
+        );
+        assert_eq!(
+            state_error("3x3:3:3 o2../.../... x 3"),
+            StateError::PieceCount {
+                first: true,
+                steps_taken: 3,
+                expected: 2,
+                len: 1,
+            }
+        );
+        assert_eq!(
+            state_error("3x3:3:3 o0o1o2/x0x1x2/... o 6"),
+            StateError::BothWin
+        );
+        assert_eq!(
+            state_error("3x3:3:3:gravity o2../.../x2.. o 2"),
+            StateError::Floating(Take { x: 0, y: 0 })
+        );
+    }
+
+    /// Makes the second oldest piece vanish instead of the oldest one.
+    #[derive(Debug, Clone)]
+    struct SecondOldestVanishes;
+    impl Ruleset for SecondOldestVanishes {
+        fn vanishing_index(&self, _setting: &GameSetting, _first: bool) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn games_round_trip_through_their_diagram() {
+        for (s, takes, finished) in [
+            ("3x4:3:4/3", "a1 b2 c3 d1 a2 b3 c1", false),
+            ("3x3:3:3:misere", "a1 b2 c3", false),
+            ("3x3:3:3", "a1 a2 b1 b2 c1", true),
+            ("4x4:3:4:torus", "c1 a2 d1 b3 a1", true),
+            ("3x3:3:3:gravity", "a1 a1 b1 b1 c1", true),
+        ] {
+            let mut game = Game::new(setting(s));
+            for take in takes.parse::<MoveList>().unwrap().0 {
+                game.add_take(take);
+            }
+            assert_eq!(game.is_finished(), finished, "{}", s);
+            let diagram = game.to_string();
+            // Pieces in a completed line are written in uppercase.
+            assert_eq!(diagram.contains('O'), finished, "{}", diagram);
+            let parsed = diagram.parse::<Game>().unwrap();
+            assert_eq!(parsed.position(), game.position(), "{}", diagram);
+        }
+
+        let diagram = Game::new(setting("3x3:3:3")).to_string();
+        let header = diagram.replacen("TicTacToeGame", "TicTacToe", 1);
+        assert!(matches!(
+            header.parse::<Game>(),
+            Err(ParsePositionError::InvalidHeader(_))
+        ));
+        let steps = diagram.replace("0 steps taken", "");
+        assert!(matches!(
+            steps.parse::<Game>(),
+            Err(ParsePositionError::MissingSteps)
+        ));
+    }
+
+    #[test]
+    fn rules_choose_the_vanishing_piece() {
+        let mut game = Game::with_rules(setting("3x3:3:3"), SecondOldestVanishes);
+        for take in ["a1", "b2", "c1", "b1", "b3", "a3"] {
+            game.add_take(take.parse().unwrap());
+        }
+        let c1 = "c1".parse().unwrap();
+        assert_eq!(game.next_vanishing().map(|piece| piece.take), Some(c1));
+        // The oldest piece of each player stays for good.
+        let schedule = game.vanish_schedule();
+        assert_eq!(schedule.len(), 4);
+        assert_eq!(schedule[0].take, c1);
+        assert!(schedule
+            .iter()
+            .all(|piece| piece.take != "a1".parse().unwrap()));
+        assert!(schedule
+            .iter()
+            .all(|piece| piece.take != "b2".parse().unwrap()));
+
+        let before = game.clone();
+        let outcome = game.add_take("c3".parse().unwrap());
+        assert_eq!(outcome.vanished, Some(c1));
+        let mut rebuilt = Game::with_rules(game.setting, SecondOldestVanishes);
+        rebuilt.replace_state(game.state().clone(), game.steps_taken());
+        assert_eq!(rebuilt.zobrist(), game.zobrist());
+        assert_eq!(rebuilt.canonical_zobrist(), game.canonical_zobrist());
+
+        game.undo();
+        assert_eq!(game.state(), before.state());
+        assert_eq!(game.zobrist(), before.zobrist());
+        game.redo();
+        assert_eq!(game.zobrist(), rebuilt.zobrist());
+    }
+
+    /// Lets a line win for the second player, whoever fills it.
+    #[derive(Debug, Clone)]
+    struct LinesWinForSecond;
+    impl Ruleset for LinesWinForSecond {
+        fn line_result(&self, _setting: &GameSetting, _first: bool) -> GameResult {
+            GameResult::SecondWin
+        }
+    }
+
+    #[test]
+    fn misere_turns_the_result_of_the_rules_around() {
+        let c1 = "c1".parse().unwrap();
+        let mut game = Game::from_position("3x3:3:3:misere o1o2./x1x2./... o 4").unwrap();
+        assert_eq!(game.add_take(c1).result, GameResult::SecondWin);
+
+        for (s, result) in [
+            ("3x3:3:3", GameResult::SecondWin),
+            ("3x3:3:3:misere", GameResult::FirstWin),
+        ] {
+            let position = Game::from_position(&format!("{} o1o2./x1x2./... o 4", s)).unwrap();
+            let mut game = Game::with_rules(setting(s), LinesWinForSecond);
+            game.replace_state(position.state().clone(), position.steps_taken());
+            assert_eq!(game.add_take(c1).result, result);
+        }
+    }
+
+    /// Knows only rows as lines and the identity as symmetry.
+    #[derive(Debug, Clone)]
+    struct RowsOnly;
+    impl Ruleset for RowsOnly {
+        fn lines(&self, setting: &GameSetting) -> Vec<WinningLine> {
+            StandardRules
+                .lines(setting)
+                .into_iter()
+                .filter(|line| line.direction == LineDirection::Row)
+                .collect()
+        }
+
+        fn symmetries(&self, _setting: &GameSetting) -> Vec<Symmetry> {
+            vec![Symmetry::Identity]
+        }
+    }
+
+    #[test]
+    fn lines_and_symmetries_of_the_rules_wrap_on_a_torus() {
+        let mask = |setting: &GameSetting, takes: &[&str]| {
+            takes.iter().fold(0, |mask, take| {
+                mask | 1 << setting.cell(take.parse().unwrap())
+            })
+        };
+        let game = Game::new(setting("3x3:3:3:torus"));
+        // Rows and columns span the board, so only the diagonals gain wrapped lines.
+        assert_eq!(game.geometry.lines.len(), 12);
+        assert_eq!(game.geometry.symmetries.len(), 8 * 9);
+
+        let game = Game::new(setting("4x4:3:4:torus"));
+        assert_eq!(game.geometry.lines.len(), 4 * 16);
+        let wrapped = mask(&game.setting, &["c1", "d1", "a1"]);
+        assert!(game.geometry.lines.contains(&(wrapped, LineDirection::Row)));
+
+        let game = Game::with_rules(setting("4x4:3:4:torus"), RowsOnly);
+        assert_eq!(game.geometry.lines.len(), 16);
+        assert!(game.geometry.lines.contains(&(wrapped, LineDirection::Row)));
+        assert_eq!(game.geometry.symmetries.len(), 16);
+        let game = Game::with_rules(setting("4x4:3:4"), RowsOnly);
+        assert_eq!(game.geometry.lines.len(), 8);
+        assert_eq!(game.geometry.symmetries.len(), 1);
+    }
+
+    /// Forbids the center cell of a 3x3 board.
+    #[derive(Debug, Clone)]
+    struct NoCenter;
+    impl Ruleset for NoCenter {
+        fn can_take(&self, game: &Game<Self>, take: Take) -> bool {
+            take != Take { x: 1, y: 1 } && game.is_free(take)
+        }
+    }
+
+    #[test]
+    fn gravity_holds_for_rules_that_restrict_takes() {
+        let mut game = Game::with_rules(setting("3x3:3:3:gravity"), NoCenter);
+        let bottom = (0..3).map(|x| Take { x, y: 2 }).collect::<Vec<_>>();
+        assert_eq!(game.valid_takes().collect::<Vec<_>>(), bottom);
+        assert_eq!(game.geometry.symmetries.len(), 2);
+
+        game.add_take(Take { x: 1, y: 0 });
+        assert_eq!(game.history(), [Take { x: 1, y: 2 }]);
+        // The center is where the next piece in the middle column would land.
+        assert_eq!(
+            game.valid_takes().collect::<Vec<_>>(),
+            [Take { x: 0, y: 2 }, Take { x: 2, y: 2 }]
+        );
+        assert_eq!(
+            game.validate_take(Take { x: 1, y: 0 }),
+            Err(TakeError::Occupied(Take { x: 1, y: 1 }))
+        );
+    }
+
+    #[test]
+    fn saves_keep_the_setting_options() {
+        let mut game = Game::new(setting("4x4:3:3/4:torus:gravity"));
+        for take in ["a1", "b1", "a1", "c1", "d1"] {
+            game.add_take(take.parse().unwrap());
+        }
+        game.undo();
+        let bytes = postcard::to_stdvec(&game).unwrap();
+        let loaded = postcard::from_bytes::<Game>(&bytes).unwrap();
+        assert_eq!(loaded.setting, game.setting);
+        assert_eq!(loaded.position(), game.position());
+        assert_eq!(loaded.history(), game.history());
+        assert!(loaded.can_redo());
+
+        // A save with a history that does not lead to its state is rejected.
+        let mut tampered = game.clone();
+        tampered.history.swap(0, 1);
+        let bytes = postcard::to_stdvec(&tampered).unwrap();
+        assert!(postcard::from_bytes::<Game>(&bytes).is_err());
+    }
+
+    #[test]
+    fn analysis_dictionaries_round_trip() {
+        for s in ["2x3:2:2", "3x3:3:2:gravity"] {
+            let dictionary = analyze(Game::new(setting(s)), usize::MAX);
+            let bytes = postcard::to_stdvec(&dictionary).unwrap();
+            assert!(matches!(
+                postcard::from_bytes::<DictionarySave>(&bytes).unwrap(),
+                DictionarySave::V2 { .. }
+            ));
+            assert_eq!(
+                postcard::from_bytes::<AnalysisDictionary>(&bytes).unwrap(),
+                dictionary
+            );
+            assert!(postcard::from_bytes::<AnalysisDictionary>(&bytes[..bytes.len() / 2]).is_err());
+
+            let v1 = postcard::to_stdvec(&DictionarySave::V1 {
+                setting: dictionary.setting,
+                analysis: dictionary.analysis.clone(),
+            })
+            .unwrap();
+            assert_eq!(
+                postcard::from_bytes::<AnalysisDictionary>(&v1).unwrap(),
+                dictionary
+            );
+        }
+    }
+
+    #[test]
+    fn best_takes_come_best_first_for_the_mover() {
+        let dictionary = analyze(Game::new(setting("2x3:2:2")), usize::MAX);
+        let mut game = Game::new(setting("2x3:2:2"));
+        for take in "a1 b1".parse::<MoveList>().unwrap().0 {
+            game.add_take(take);
+        }
+        let best = dictionary.best_takes(&game);
+        let notation = |takes: &[(Take, GameAnalysis)]| {
+            takes
+                .iter()
+                .map(|(take, analysis)| (take.to_string(), analysis.clone()))
+                .collect::<Vec<_>>()
+        };
+        // Equally good takes keep the row-major order.
+        assert_eq!(
+            notation(&best),
+            [
+                ("a2".to_string(), GameAnalysis::Winning(0)),
+                ("b2".to_string(), GameAnalysis::Winning(0)),
+                ("c1".to_string(), GameAnalysis::Losing(1)),
+                ("c2".to_string(), GameAnalysis::Losing(1)),
+            ]
+        );
+        // The analysis of each take is that of the position it leads to, turned around.
+        for (take, analysis) in &best {
+            let mut next = game.clone();
+            next.add_take(*take);
+            assert_eq!(dictionary.get(&next).cloned().unwrap().flip(), *analysis);
+        }
+
+        // Every kind of analysis is ranked from the point of view of the mover.
+        // No symmetry maps a1 onto itself, so the takes lead to distinct positions.
+        let mut start = Game::new(setting("3x4:3:4"));
+        start.add_take(Take { x: 0, y: 0 });
+        let mut analysis = HashMap::new();
+        for (take, successor) in [
+            ("b1", Some(GameAnalysis::Draw)),
+            ("c1", None),
+            ("d1", Some(GameAnalysis::Losing(4))),
+            ("a2", Some(GameAnalysis::Losing(0))),
+            ("b2", Some(GameAnalysis::Winning(1))),
+            ("c2", Some(GameAnalysis::Winning(3))),
+        ] {
+            let mut next = start.clone();
+            next.add_take(take.parse().unwrap());
+            if let Some(successor) = successor {
+                analysis.insert(next.analysis_key(), successor);
+            }
+        }
+        let dictionary = AnalysisDictionary {
+            setting: start.setting,
+            analysis,
+        };
+        // The fastest win comes first and the quickest loss last, as the loser resists the
+        // longest.
+        let best = dictionary.best_takes(&start);
+        assert_eq!(
+            notation(&best[..7]),
+            [
+                ("a2".to_string(), GameAnalysis::Winning(0)),
+                ("d1".to_string(), GameAnalysis::Winning(4)),
+                ("b1".to_string(), GameAnalysis::Draw),
+                ("c1".to_string(), GameAnalysis::Unknown),
+                ("d2".to_string(), GameAnalysis::Unknown),
+                ("a3".to_string(), GameAnalysis::Unknown),
+                ("b3".to_string(), GameAnalysis::Unknown),
+            ]
+        );
+        assert_eq!(
+            notation(&best[best.len() - 2..]),
+            [
+                ("c2".to_string(), GameAnalysis::Losing(3)),
+                ("b2".to_string(), GameAnalysis::Losing(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn principal_variation_plays_to_the_end() {
+        for (s, winner) in [
+            ("2x3:2:2", GameResult::FirstWin),
+            ("3x3:3:3/4", GameResult::SecondWin),
+        ] {
+            let start = Game::new(setting(s));
+            let dictionary = analyze(start.clone(), usize::MAX);
+            let line = dictionary.principal_variation(&start);
+            let mut game = start.clone();
+            for (ply, (take, analysis)) in line.iter().enumerate() {
+                // The count falls by one each ply, down to the take that ends the game.
+                let count = line.len() - 1 - ply;
+                let expected = if (line.len() - ply) % 2 == 1 {
+                    GameAnalysis::Winning(count)
+                } else {
+                    GameAnalysis::Losing(count)
+                };
+                assert_eq!(*analysis, expected, "{} ply {}", s, ply);
+                assert!(!game.is_finished());
+                game.add_take(*take);
+            }
+            assert!(game.is_finished(), "{}", s);
+            assert_eq!(game.result(), winner, "{}", s);
+        }
+        let drawn = Game::new(setting("3x3:3:3"));
+        let dictionary = analyze(drawn.clone(), usize::MAX);
+        assert_eq!(dictionary.get(&drawn), Some(&GameAnalysis::Draw));
+        assert_eq!(dictionary.principal_variation(&drawn), []);
+    }
+
+    #[test]
+    fn state_indexer_numbers_every_key_once() {
+        for s in ["2x2:2:1", "2x3:2:2/1", "3x3:3:2:gravity", "3x3:3:3:torus"] {
+            let indexer = StateIndexer::try_new(setting(s)).unwrap();
+            for index in 0..indexer.len() {
+                let key = indexer.unrank(index).unwrap();
+                assert_eq!(indexer.rank(&key), Some(index), "{} {:?}", s, key);
+            }
+            assert_eq!(indexer.unrank(indexer.len()), None);
+            let dictionary = analyze(Game::new(setting(s)), usize::MAX);
+            let ranks = dictionary
+                .analysis()
+                .keys()
+                .map(|key| indexer.rank(key).unwrap())
+                .collect::<HashSet<_>>();
+            assert_eq!(ranks.len(), dictionary.analysis().len());
+        }
+        let indexer = StateIndexer::try_new(setting("2x2:2:1")).unwrap();
+        // States with up to 2 takes out of 4 cells, each with either player to move.
+        assert_eq!(indexer.len(), 2 * (1 + 4 + 4 * 3));
+        let key = |takes: &[Take]| {
+            AnalysisKey::new(
+                BoardState {
+                    takes: takes.to_vec(),
+                },
+                true,
+            )
+        };
+        let a1 = Take { x: 0, y: 0 };
+        assert_eq!(indexer.rank(&key(&[a1, a1])), None);
+        assert_eq!(indexer.rank(&key(&[a1, Take { x: 2, y: 0 }])), None);
+        assert_eq!(
+            indexer.rank(&key(&[a1, Take { x: 1, y: 0 }, Take { x: 0, y: 1 }])),
+            None
+        );
+        assert!(StateIndexer::try_new(setting("11x11:3:60")).is_none());
+    }
+
+    #[test]
+    fn canonical_state_indexer_numbers_each_position_once() {
+        for s in ["2x3:2:2/1", "3x3:3:3", "3x3:3:2:gravity", "3x3:3:2:torus"] {
+            let setting = setting(s);
+            let indexer = CanonicalStateIndexer::try_new(setting).unwrap();
+            for index in 0..indexer.len() {
+                let key = indexer.unrank(index).unwrap();
+                assert!(key.state.is_normalized(&setting), "{} {:?}", s, key);
+                assert_eq!(key.state.verify(&setting), Ok(()));
+                for transform in Transform::all(&setting) {
+                    let moved =
+                        AnalysisKey::new(key.state.apply(transform, &setting), key.is_next_first);
+                    assert_eq!(indexer.rank(&moved), Some(index), "{} {:?}", s, moved);
+                }
+            }
+            assert_eq!(indexer.unrank(indexer.len()), None);
+            // Every position reached is numbered, and so is every other legal one.
+            let dictionary = analyze(Game::new(setting), usize::MAX);
+            let ranks = dictionary
+                .analysis()
+                .keys()
+                .map(|key| indexer.rank(key).unwrap())
+                .collect::<HashSet<_>>();
+            assert_eq!(ranks.len(), dictionary.analysis().len());
+        }
+        let indexer = CanonicalStateIndexer::try_new(setting("3x3:3:3")).unwrap();
+        // The first player has moved last, with more pieces than the second one.
+        let key = AnalysisKey::new(
+            BoardState {
+                takes: vec![Take { x: 1, y: 1 }],
+            },
+            false,
+        );
+        assert_eq!(indexer.rank(&key), Some(3));
+        assert_eq!(indexer.rank(&AnalysisKey::new(key.state, true)), None);
+    }
+
+    #[test]
+    fn truncated_search_leaves_positions_unknown() {
+        // Without vanishing, tic-tac-toe is a draw once every position is searched.
+        let start = Game::new(setting("3x3:3:9"));
+        let full = analyze(start.clone(), usize::MAX);
+        assert_eq!(full.get(&start), Some(&GameAnalysis::Draw));
+        assert!(full
+            .analysis()
+            .values()
+            .all(|a| *a != GameAnalysis::Unknown));
+        let truncated = analyze(start.clone(), 1);
+        assert_eq!(truncated.get(&start), Some(&GameAnalysis::Unknown));
+        assert!(truncated
+            .analysis()
+            .values()
+            .all(|a| *a != GameAnalysis::Draw));
+
+        // A win found before the cut is kept, and the takes that were not searched to the
+        // end are unknown.
+        let game = Game::from_position("3x3:3:3 o1o2./x1x2./... o 4").unwrap();
+        let truncated = analyze(game.clone(), 10);
+        assert_eq!(truncated.get(&game), Some(&GameAnalysis::Winning(1)));
+        let mut blocked = game.clone();
+        blocked.add_take("b3".parse().unwrap());
+        assert_eq!(truncated.get(&blocked), Some(&GameAnalysis::Unknown));
+    }
+
+    #[test]
+    fn symmetries_are_undone_by_their_inverse() {
+        for s in [
+            "3x3:3:3",
+            "3x4:3:3",
+            "4x4:3:4:torus",
+            "3x4:3:3:torus:gravity",
+        ] {
+            let setting = setting(s);
+            let cells = (0..setting.board_height() * setting.board_width())
+                .map(|cell| setting.take(cell))
+                .collect::<Vec<_>>();
+            for transform in Transform::all(&setting) {
+                let images = cells
+                    .iter()
+                    .map(|take| take.apply(transform, &setting))
+                    .collect::<HashSet<_>>();
+                assert_eq!(images.len(), cells.len(), "{} {:?}", s, transform);
+                for take in &cells {
+                    assert_eq!(
+                        take.apply(transform, &setting).inverse(transform, &setting),
+                        *take
+                    );
+                    assert_eq!(
+                        take.inverse(transform, &setting).apply(transform, &setting),
+                        *take
+                    );
+                }
+            }
+            for &symmetry in Symmetry::all(&setting) {
+                for take in &cells {
+                    assert_eq!(
+                        take.apply(symmetry, &setting)
+                            .apply(symmetry.inverse(), &setting),
+                        *take
+                    );
+                }
+            }
+        }
+
+        let setting = setting("3x3:3:3");
+        let state = BoardState {
+            takes: ["a1", "b1", "c2", "a3"]
+                .iter()
+                .map(|take| take.parse().unwrap())
+                .collect(),
+        };
+        assert_eq!(
+            state.apply(Symmetry::Rotate90, &setting),
+            state.rotate(&setting)
+        );
+        assert_eq!(
+            state.apply(Symmetry::MirrorX, &setting),
+            state.mirror_x(&setting)
+        );
+        assert_eq!(
+            state.apply(Symmetry::MirrorY, &setting),
+            state.mirror_y(&setting)
+        );
+        let (normalized, transform) = state.normalized_with_symmetry(&setting);
+        assert_eq!(state.apply(transform, &setting), normalized);
+        assert_eq!(normalized.inverse(transform, &setting), state);
+    }
+
+    #[test]
+    fn takes_round_trip_through_their_notation() {
+        for y in 0..11 {
+            for x in 0..11 {
+                let take = Take { x, y };
+                assert_eq!(take.to_string().parse(), Ok(take));
+            }
+        }
+        assert_eq!("b3".parse(), Ok(Take { x: 1, y: 2 }));
+        assert_eq!("".parse::<Take>(), Err(ParseTakeError::Empty));
+        assert_eq!(
+            "l1".parse::<Take>(),
+            Err(ParseTakeError::InvalidColumn('l'))
+        );
+        for row in ["", "0", "12", "+1", "01x"] {
+            assert_eq!(
+                format!("a{}", row).parse::<Take>(),
+                Err(ParseTakeError::InvalidRow(row.to_string()))
+            );
+        }
+
+        let moves = "b2 a1 k11".parse::<MoveList>().unwrap();
+        assert_eq!(moves.to_string(), "b2 a1 k11");
+        assert!("b2 a1 z1".parse::<MoveList>().is_err());
+    }
+
+    #[test]
+    fn settings_round_trip_through_their_text() {
+        for s in [
+            "3x3:3:3",
+            "3x4:3:3/4",
+            "11x11:5:127",
+            "3x3:3:3:misere",
+            "4x4:3:4:torus:gravity",
+            "3x3:3:3/4:misere:torus:gravity",
+        ] {
+            assert_eq!(setting(s).to_string(), s);
+        }
+        let parsed = setting("4x4:3:4:gravity:misere");
+        assert!(parsed.is_misere() && parsed.is_gravity() && !parsed.is_torus());
+        assert_eq!(parsed.to_string(), "4x4:3:4:misere:gravity");
+        assert_eq!(setting("3x3:3:3/3"), setting("3x3:3:3"));
+
+        for s in [
+            "",
+            "3x3:3",
+            "3x3:3:3:",
+            "3x3:3:3:other",
+            "3:3:3",
+            "3x3:3:3/",
+        ] {
+            assert_eq!(
+                s.parse::<GameSetting>(),
+                Err(ParseSettingError::InvalidFormat(s.to_string()))
+            );
+        }
+        assert_eq!(
+            "3x3:4:3".parse::<GameSetting>(),
+            Err(ParseSettingError::Setting(SettingError::GoalOutOfRange {
+                goal: 4,
+                max: 3
+            }))
+        );
+        assert_eq!(
+            "3x3:3:0/3".parse::<GameSetting>(),
+            Err(ParseSettingError::Setting(
+                SettingError::PieceLimitOutOfRange(0)
+            ))
+        );
+    }
+}